@@ -54,7 +54,7 @@ struct ExciteEngine {
 
 impl ExciteEngine {
     fn new(status: f32, receiver: crossbeam::channel::Receiver<Message>, port_out: Port<AudioOut>) -> ExciteEngine {
-        let ee = ExciteEngine {
+        ExciteEngine {
             status,
             receiver,
             port_out,
@@ -62,8 +62,7 @@ impl ExciteEngine {
             target: 0.0,
             pole: 0.0,
             gate_old: 0.0
-        };
-        ee
+        }
     }
 
     fn ratio2pole(t: f32, ratio: f32, sr: usize) -> f32 {
@@ -149,7 +148,7 @@ fn main() {
         process::exit(1);
     }
 
-    let port_out = client.register_port("out", AudioOut::default()).unwrap();
+    let port_out = client.register_port("out", AudioOut).unwrap();
 
     let (sender, receiver) = crossbeam::channel::unbounded();
 
@@ -0,0 +1,908 @@
+// Sizes the delay line of every voice
+pub const LOWEST_FREQUENCY: f32 = 20.0;
+// A released voice quieter than this is free for a new note
+pub const SILENCE: f32 = 0.0001;
+// Per sample decay of the level follower that finds the quietest voice
+pub const LEVEL_DECAY: f32 = 0.9995;
+// Pitch bend range in semitones
+pub const BEND_RANGE: f32 = 2.0;
+// Loop gain of a damped string after note off
+pub const DAMPED_FEEDBACK: f32 = 0.5;
+// Feedback changes are spread over this time to avoid clicks
+pub const FEEDBACK_SMOOTHING_MS: f32 = 20.0;
+// Notes below this frequency ring longer when the decay is stretched
+pub const DECAY_REFERENCE: f32 = 440.0;
+// Start of the noise of the first voice, the others get multiples of it
+pub const NOISE_SEED: u32 = 0x9e3779b9;
+// Pole of the lowpass for filtered noise excitation
+pub const NOISE_POLE: f32 = 0.7;
+// Strongest pick direction lowpass, Jaffe and Smith use 0.9 for a down stroke
+pub const MAX_PICK_DIRECTION: f32 = 0.9;
+// Keeps the one pole loss filters away from a pole at DC
+pub const MAX_LOSS_POLE: f32 = 0.99;
+// Smallest allpass delay, below it the allpass pole gets close to the unit circle
+pub const MIN_ALLPASS_DELAY: f32 = 0.1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Excitation {
+    Noise,
+    FilteredNoise,
+    Impulse,
+    Sample
+}
+
+// How the fractional part of the string length is realized
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Interpolation {
+    Linear,
+    // First order allpass after Jaffe and Smith, keeps the highs that interpolation damps
+    Allpass
+}
+
+// Lowpass in the feedback path, sets how much faster the highs decay than the lows
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LossFilter {
+    // Two tap average of the original algorithm
+    Average,
+    // One pole lowpass with pole 1 - brightness, as fitted by `wave-rs kps-fit`
+    OnePole { brightness: f32 },
+    // Symmetric three taps, damping 0 passes everything and 1 is [0.25, 0.5, 0.25]
+    ThreeTap { damping: f32 },
+    // One pole lowpass decaying the lows in t60_low and the highs in t60_high seconds at full feedback
+    Bands { t60_low: f32, t60_high: f32 }
+}
+
+impl LossFilter {
+    // Gain and pole of the one pole filters for a string at the frequency
+    pub fn one_pole(&self, frequency: f32) -> (f32, f32) {
+        match *self {
+            LossFilter::OnePole { brightness } => (1.0, (1.0 - brightness).clamp(0.0, MAX_LOSS_POLE)),
+            LossFilter::Bands { t60_low, t60_high } => {
+                let low = 10f32.powf(-3.0 / (frequency * t60_low));
+                let high = 10f32.powf(-3.0 / (frequency * t60_high));
+                (low, ((low - high) / (low + high)).clamp(0.0, MAX_LOSS_POLE))
+            }
+            _ => (1.0, 0.0)
+        }
+    }
+
+    // Gain at the angular frequency, the loop gain makes up for it to keep the decay time
+    pub fn magnitude(&self, w: f32, gain: f32, pole: f32) -> f32 {
+        match *self {
+            LossFilter::Average => (w / 2.0).cos(),
+            LossFilter::ThreeTap { damping } => 1.0 - damping / 2.0 + damping / 2.0 * w.cos(),
+            LossFilter::OnePole { .. } | LossFilter::Bands { .. } => gain * (1.0 - pole) / (1.0 - 2.0 * pole * w.cos() + pole * pole).sqrt()
+        }
+    }
+
+    // Phase delay in samples at the angular frequency, taken off the delay line to keep the string in tune
+    pub fn delay(&self, w: f32, pole: f32) -> f32 {
+        match self {
+            LossFilter::Average => 0.5,
+            LossFilter::ThreeTap { .. } => 1.0,
+            LossFilter::OnePole { .. } | LossFilter::Bands { .. } => (pole * w.sin()).atan2(1.0 - pole * w.cos()) / w
+        }
+    }
+}
+
+// How a smoothed parameter moves to a new value
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Glide {
+    // Constant rate, arrives after the glide time
+    Linear,
+    // One pole, the glide time is its time constant
+    Exponential
+}
+
+// Moves a parameter to its target over a time in milliseconds, independent of the sample rate
+pub struct Smoother {
+    value: f32,
+    target: f32,
+    glide: Glide,
+    // Glide time in samples, below one sample the value jumps
+    samples: f32,
+    // Per sample increment of a linear glide and coefficient of an exponential one
+    step: f32,
+    coeff: f32
+}
+
+impl Smoother {
+    pub fn new(glide: Glide) -> Smoother {
+        Smoother { value: 0.0, target: 0.0, glide, samples: 0.0, step: 0.0, coeff: 1.0 }
+    }
+
+    pub fn set_time(&mut self, ms: f32, sr: f32) {
+        self.samples = ms * sr / 1000.0;
+        self.coeff = if self.samples < 1.0 { 1.0 } else { 1.0 - (-1.0 / self.samples).exp() };
+        self.step = (self.target - self.value) / self.samples.max(1.0);
+    }
+
+    pub fn jump(&mut self, value: f32) {
+        self.value = value;
+        self.target = value;
+        self.step = 0.0;
+    }
+
+    pub fn tick(&mut self, target: f32) -> f32 {
+        if target != self.target {
+            self.target = target;
+            self.step = (target - self.value) / self.samples.max(1.0);
+        }
+        if self.samples < 1.0 {
+            self.value = target;
+            return self.value;
+        }
+        match self.glide {
+            Glide::Linear => {
+                if (target - self.value).abs() <= self.step.abs() {
+                    self.value = target;
+                } else {
+                    self.value += self.step;
+                }
+            }
+            Glide::Exponential => self.value += self.coeff * (target - self.value)
+        }
+        self.value
+    }
+}
+
+// T60 of a note, notes below DECAY_REFERENCE ring longer by (DECAY_REFERENCE / frequency)^stretch
+pub fn decay_time(t60: f32, stretch: f32, frequency: f32) -> f32 {
+    t60 * (DECAY_REFERENCE / frequency).max(1.0).powf(stretch)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stealing {
+    Oldest,
+    Quietest
+}
+
+pub enum AudioControlMessage {
+    Feedback(f32),
+    // Retunes the most recent note
+    Frequency(f32),
+    NoteOn(f32),
+    NoteOff(f32),
+    Stealing(Stealing),
+    Damping(bool),
+    Excitation(Excitation),
+    Interpolation(Interpolation),
+    LossFilter(LossFilter),
+    PickPosition(f32),
+    PickDirection(f32),
+    // Decay time in seconds, None uses the raw feedback
+    Decay(Option<f32>),
+    DecayStretch(f32),
+    // Glide time in milliseconds, for retuning and from one note to the next
+    GlideTime(f32),
+    Glide(Glide),
+    // Excites the most recent note again, starts one at the frequency if none is held
+    Pluck(f32)
+}
+
+// Circular delay line, read back by how many samples ago a value was written
+pub struct DelayLine {
+    buffer: Vec<f32>,
+    // Where the next sample goes
+    write: usize
+}
+
+impl DelayLine {
+    pub fn new(max_delay: usize) -> DelayLine {
+        DelayLine { buffer: vec![0.0; max_delay.max(2)], write: 0 }
+    }
+
+    // Holds one period of the lowest frequency, plus the taps the loss filter reads past it
+    pub fn for_frequency(lowest: f32, sr: f32) -> DelayLine {
+        DelayLine::new((sr / lowest).ceil() as usize + 4)
+    }
+
+    pub fn max_delay(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.fill(0.0);
+        self.write = 0;
+    }
+
+    pub fn write(&mut self, x: f32) {
+        self.buffer[self.write] = x;
+        self.write += 1;
+        if self.write == self.buffer.len() {
+            self.write = 0;
+        }
+    }
+
+    // Sample written delay samples ago, 1 is the newest, clamped to what the line holds
+    pub fn read(&self, delay: usize) -> f32 {
+        let delay = delay.clamp(1, self.buffer.len());
+        let i = if self.write >= delay { self.write - delay } else { self.write + self.buffer.len() - delay };
+        self.buffer[i]
+    }
+
+    // Linear interpolation between the samples around a fractional delay
+    pub fn read_fractional(&self, delay: f32) -> f32 {
+        let delay = delay.clamp(1.0, (self.buffer.len() - 1) as f32);
+        let integer = delay.floor();
+        let fract = delay - integer;
+        (1.0 - fract) * self.read(integer as usize) + fract * self.read(integer as usize + 1)
+    }
+}
+
+// Signal fed into a string when it is plucked
+pub struct Exciter {
+    // Samples left to play, the filters ring on after the source_len samples of the source
+    remaining: usize,
+    source_len: usize,
+    pos: usize,
+    level: f32,
+    lowpass: f32,
+    // Pick direction lowpass, pole 0 is an up stroke
+    direction: f32,
+    direction_y: f32,
+    // Pick position comb 1 - z^-comb_delay, 0 leaves the excitation unfiltered
+    comb_delay: usize,
+    history: DelayLine,
+    // State of the noise generator, never 0
+    seed: u32
+}
+
+impl Exciter {
+    pub fn new(len: usize) -> Exciter {
+        Exciter {
+            remaining: 0,
+            source_len: 0,
+            pos: 0,
+            level: 0.0,
+            lowpass: 0.0,
+            direction: 0.0,
+            direction_y: 0.0,
+            comb_delay: 0,
+            history: DelayLine::new(len),
+            seed: NOISE_SEED
+        }
+    }
+
+    pub fn trigger(&mut self, len: usize, level: f32, comb_delay: usize, direction: f32) {
+        self.comb_delay = comb_delay.min(self.history.max_delay());
+        self.direction = direction.clamp(0.0, MAX_PICK_DIRECTION);
+        let tail = if self.direction > 0.0 { (SILENCE.ln() / self.direction.ln()).ceil() as usize } else { 0 };
+        self.source_len = len;
+        self.remaining = len + tail + self.comb_delay;
+        self.pos = 0;
+        self.level = level;
+        self.lowpass = 0.0;
+        self.direction_y = 0.0;
+        self.history.clear();
+    }
+
+    pub fn tick(&mut self, excitation: Excitation, sample: &[f32]) -> f32 {
+        if self.remaining == 0 {
+            return 0.0;
+        }
+        let source = if self.pos < self.source_len {
+            // Xorshift, the same seed always plucks the same string
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 17;
+            self.seed ^= self.seed << 5;
+            let noise = 2.0 * (self.seed as f32 / u32::MAX as f32) - 1.0;
+            match excitation {
+                Excitation::Noise => noise,
+                Excitation::FilteredNoise => {
+                    self.lowpass = (1.0 - NOISE_POLE) * noise + NOISE_POLE * self.lowpass;
+                    self.lowpass
+                }
+                Excitation::Impulse => 1.0,
+                Excitation::Sample => sample.get(self.pos).copied().unwrap_or(0.0)
+            }
+        } else {
+            0.0
+        };
+        self.remaining -= 1;
+
+        self.direction_y = (1.0 - self.direction) * source + self.direction * self.direction_y;
+        let out = if self.comb_delay > 0 {
+            self.direction_y - self.history.read(self.comb_delay)
+        } else {
+            self.direction_y
+        };
+        self.history.write(self.direction_y);
+        self.pos += 1;
+        self.level * out
+    }
+}
+
+// One string of the pool
+pub struct Voice {
+    delay_line: DelayLine,
+    frequency: f32,
+    // Frequency the note started at, note off finds the voice by it or by the current frequency
+    note: f32,
+    feedback: f32,
+    delay: Smoother,
+    feedback_mem: Smoother,
+    // Note is on, the external input only excites held voices
+    held: bool,
+    started: u64,
+    level: f32,
+    exciter: Exciter,
+    pub interpolation: Interpolation,
+    pub loss: LossFilter,
+    // Decay time in seconds, feedback then scales the loop gain that gives it
+    pub t60: Option<f32>,
+    pub decay_stretch: f32,
+    // Last output of the one pole loss filters
+    lowpass: f32,
+    // Last input and output of the allpass
+    allpass_x: f32,
+    allpass_y: f32
+}
+
+impl Voice {
+    pub fn new(lowest: f32, sr: f32) -> Voice {
+        let delay_line = DelayLine::for_frequency(lowest, sr);
+        let exciter = Exciter::new(delay_line.max_delay());
+        let mut feedback_mem = Smoother::new(Glide::Exponential);
+        feedback_mem.set_time(FEEDBACK_SMOOTHING_MS, sr);
+        Voice {
+            delay_line,
+            frequency: 220.0,
+            note: 220.0,
+            feedback: 0.0,
+            delay: Smoother::new(Glide::Exponential),
+            feedback_mem,
+            held: false,
+            started: 0,
+            level: 0.0,
+            exciter,
+            interpolation: Interpolation::Allpass,
+            loss: LossFilter::Average,
+            t60: None,
+            decay_stretch: 0.0,
+            lowpass: 0.0,
+            allpass_x: 0.0,
+            allpass_y: 0.0
+        }
+    }
+
+    // Starts a new note on a silent string, without gliding from the previous one
+    pub fn start(&mut self, frequency: f32, feedback: f32, sr: f32, now: u64) {
+        self.delay_line.clear();
+        self.frequency = frequency;
+        self.note = frequency;
+        self.feedback = feedback;
+        self.delay.jump(sr / frequency);
+        self.feedback_mem.jump(feedback);
+        self.held = true;
+        self.started = now;
+        self.level = 0.0;
+        self.lowpass = 0.0;
+        self.allpass_x = 0.0;
+        self.allpass_y = 0.0;
+    }
+
+    pub fn is_free(&self) -> bool {
+        !self.held && self.level < SILENCE
+    }
+
+    // The loop delay of the delay line, loss filter and fractional delay together is one period
+    pub fn tick(&mut self, input: f32, bend: f32, sr: f32) -> f32 {
+        let delay = self.delay.tick(sr / (self.frequency * bend));
+        let feedback_mem = self.feedback_mem.tick(self.feedback);
+
+        let (gain, pole) = self.loss.one_pole(sr / delay);
+        let w = 2.0 * std::f32::consts::PI / delay;
+        let length = (delay - self.loss.delay(w, pole)).clamp(1.0 + MIN_ALLPASS_DELAY, (self.delay_line.max_delay() - 3) as f32);
+
+        // The three newest samples of the delay line that are one length old
+        let (taps, fraction) = match self.interpolation {
+            Interpolation::Linear => {
+                let line = &self.delay_line;
+                ([line.read_fractional(length), line.read_fractional(length + 1.0), line.read_fractional(length + 2.0)], 0.0)
+            }
+            Interpolation::Allpass => {
+                let integer = (length - MIN_ALLPASS_DELAY).floor();
+                let i = integer as usize;
+                let line = &self.delay_line;
+                ([line.read(i), line.read(i + 1), line.read(i + 2)], length - integer)
+            }
+        };
+
+        let loss = match self.loss {
+            LossFilter::Average => 0.5*taps[0] + 0.5*taps[1],
+            LossFilter::ThreeTap { damping } => {
+                let side = damping / 4.0;
+                side * taps[0] + (1.0 - 2.0 * side) * taps[1] + side * taps[2]
+            }
+            LossFilter::OnePole { .. } | LossFilter::Bands { .. } => {
+                self.lowpass = gain * (1.0 - pole) * taps[0] + pole * self.lowpass;
+                self.lowpass
+            }
+        };
+
+        let looped = match self.interpolation {
+            Interpolation::Linear => loss,
+            Interpolation::Allpass => {
+                let c = (1.0 - fraction) / (1.0 + fraction);
+                let out = c * loss + self.allpass_x - c * self.allpass_y;
+                self.allpass_x = loss;
+                self.allpass_y = out;
+                out
+            }
+        };
+        // The bands carry their own decay in the loop filter, feedback still damps and scales it
+        let feedback = match (self.loss, self.t60) {
+            (LossFilter::Bands { .. }, _) => feedback_mem,
+            (_, None) => feedback_mem,
+            (_, Some(t60)) => {
+                let frequency = sr / delay;
+                let decay = 10f32.powf(-3.0 / (frequency * decay_time(t60, self.decay_stretch, frequency)));
+                (decay / self.loss.magnitude(w, gain, pole)).min(1.0) * feedback_mem
+            }
+        };
+
+        let out = input + feedback * looped;
+
+        self.delay_line.write(out);
+
+        self.level = f32::max(out.abs(), LEVEL_DECAY * self.level);
+        out
+    }
+}
+
+pub struct VoicePool {
+    pub voices: Vec<Voice>,
+    stealing: Stealing,
+    // Feedback of new notes
+    feedback: f32,
+    // Frequency factor of the pitch bend wheel, shared by all voices
+    bend: f32,
+    // Note off damps the string instead of letting it ring
+    pub damping: bool,
+    // Controller number mapped to feedback
+    pub feedback_cc: u8,
+    pub excitation: Excitation,
+    // Excitation for Excitation::Sample, empty if none was loaded
+    pub sample: Vec<f32>,
+    // Pluck point as a fraction of the string length and pick direction lowpass pole
+    pub pick_position: f32,
+    pub pick_direction: f32,
+    // Retuning and new notes glide from the previous pitch, 0 retunes at once
+    glide_time: f32,
+    glide: Glide,
+    last_frequency: Option<f32>,
+    // Counts notes, orders the voices by age
+    clock: u64
+}
+
+impl VoicePool {
+    pub fn new(count: usize, sr: f32, stealing: Stealing, feedback: f32) -> VoicePool {
+        VoicePool {
+            voices: (0..count.max(1))
+                .map(|i| {
+                    let mut voice = Voice::new(LOWEST_FREQUENCY, sr);
+                    voice.exciter.seed = NOISE_SEED.wrapping_mul(2 * i as u32 + 1);
+                    voice
+                })
+                .collect(),
+            stealing,
+            feedback,
+            bend: 1.0,
+            damping: false,
+            feedback_cc: 1,
+            excitation: Excitation::Noise,
+            sample: Vec::new(),
+            pick_position: 0.0,
+            pick_direction: 0.0,
+            glide_time: 0.0,
+            glide: Glide::Exponential,
+            last_frequency: None,
+            clock: 0
+        }
+    }
+
+    // A free voice if there is one, otherwise the oldest or quietest, preferring released voices
+    pub fn allocate(&self) -> usize {
+        if let Some(i) = self.voices.iter().position(|v| v.is_free()) {
+            return i;
+        }
+        let released = self.voices.iter().any(|v| !v.held);
+        let candidates = self.voices.iter().enumerate().filter(|(_, v)| !released || !v.held);
+        let chosen = match self.stealing {
+            Stealing::Oldest => candidates.min_by_key(|(_, v)| v.started),
+            Stealing::Quietest => candidates.min_by(|(_, a), (_, b)| a.level.total_cmp(&b.level))
+        };
+        chosen.map(|(i, _)| i).unwrap_or(0)
+    }
+
+    pub fn note_on(&mut self, frequency: f32, pluck: f32, sr: f32) {
+        let i = self.allocate();
+        self.clock += 1;
+        self.voices[i].start(frequency, self.feedback, sr, self.clock);
+        if let Some(last) = self.last_frequency.filter(|_| self.glide_time > 0.0) {
+            self.voices[i].delay.jump(sr / last);
+        }
+        self.last_frequency = Some(frequency);
+        self.excite(i, pluck, sr);
+    }
+
+    pub fn set_glide(&mut self, glide_time: f32, glide: Glide, sr: f32) {
+        self.glide_time = glide_time;
+        self.glide = glide;
+        for voice in self.voices.iter_mut() {
+            voice.delay.glide = glide;
+            voice.delay.set_time(glide_time, sr);
+        }
+    }
+
+    // Noise lasts one period of the string, like the delay line filling of the classic algorithm
+    pub fn excite(&mut self, i: usize, level: f32, sr: f32) {
+        let len = match self.excitation {
+            Excitation::Noise | Excitation::FilteredNoise => (sr / self.voices[i].frequency) as usize + 1,
+            Excitation::Impulse => 1,
+            Excitation::Sample => self.sample.len()
+        };
+        let comb_delay = (self.pick_position * sr / self.voices[i].frequency).round() as usize;
+        self.voices[i].exciter.trigger(len, level, comb_delay, self.pick_direction);
+    }
+
+    // Plucks the most recent note, or starts one if none is held
+    pub fn pluck(&mut self, frequency: f32, sr: f32) {
+        let newest = (0..self.voices.len())
+            .filter(|i| self.voices[*i].held)
+            .max_by_key(|i| self.voices[*i].started);
+        match newest {
+            Some(i) => self.excite(i, 1.0, sr),
+            None => self.note_on(frequency, 1.0, sr)
+        }
+    }
+
+    pub fn note_off(&mut self, frequency: f32) {
+        for voice in self.voices.iter_mut().filter(|v| v.held && (v.note == frequency || v.frequency == frequency)) {
+            voice.held = false;
+            if self.damping {
+                voice.feedback = voice.feedback.min(DAMPED_FEEDBACK);
+            }
+        }
+    }
+
+    pub fn set_feedback(&mut self, feedback: f32) {
+        self.feedback = feedback;
+        for voice in self.voices.iter_mut().filter(|v| v.held) {
+            voice.feedback = feedback;
+        }
+    }
+
+    // Applies a message of the gui, the same way live and offline
+    pub fn control(&mut self, message: AudioControlMessage, sr: f32) {
+        match message {
+            AudioControlMessage::Feedback(val) => {
+                self.set_feedback(val);
+            }
+            AudioControlMessage::Frequency(val) => {
+                if let Some(voice) = self.newest() {
+                    voice.frequency = val;
+                }
+                self.last_frequency = Some(val);
+            }
+            AudioControlMessage::NoteOn(val) => {
+                self.note_on(val, 1.0, sr);
+            }
+            AudioControlMessage::NoteOff(val) => {
+                self.note_off(val);
+            }
+            AudioControlMessage::Stealing(val) => {
+                self.stealing = val;
+            }
+            AudioControlMessage::Damping(val) => {
+                self.damping = val;
+            }
+            AudioControlMessage::Excitation(val) => {
+                self.excitation = val;
+            }
+            AudioControlMessage::Decay(val) => {
+                // The decay time sets the loop gain, feedback starts again from 1.0
+                if val.is_some() {
+                    self.set_feedback(1.0);
+                }
+                for voice in self.voices.iter_mut() {
+                    voice.t60 = val;
+                }
+            }
+            AudioControlMessage::DecayStretch(val) => {
+                for voice in self.voices.iter_mut() {
+                    voice.decay_stretch = val;
+                }
+            }
+            AudioControlMessage::GlideTime(val) => {
+                self.set_glide(val, self.glide, sr);
+            }
+            AudioControlMessage::Glide(val) => {
+                self.set_glide(self.glide_time, val, sr);
+            }
+            AudioControlMessage::PickPosition(val) => {
+                self.pick_position = val;
+            }
+            AudioControlMessage::PickDirection(val) => {
+                self.pick_direction = val;
+            }
+            AudioControlMessage::Interpolation(val) => {
+                for voice in self.voices.iter_mut() {
+                    voice.interpolation = val;
+                }
+            }
+            AudioControlMessage::LossFilter(val) => {
+                for voice in self.voices.iter_mut() {
+                    voice.loss = val;
+                }
+            }
+            AudioControlMessage::Pluck(val) => {
+                self.pluck(val, sr);
+            }
+        }
+    }
+
+    // Note on and off, pitch bend and the feedback controller, other messages are ignored
+    pub fn midi(&mut self, bytes: &[u8], sr: f32) {
+        if bytes.len() < 3 {
+            return;
+        }
+        let frequency = |note: u8| 440.0 * 2f32.powf((note as f32 - 69.0) / 12.0);
+        match (bytes[0] & 0xf0, bytes[1], bytes[2]) {
+            (0x90, note, velocity) if velocity > 0 => self.note_on(frequency(note), velocity as f32 / 127.0, sr),
+            (0x80, note, _) | (0x90, note, _) => self.note_off(frequency(note)),
+            (0xe0, lsb, msb) => {
+                let value = ((msb as i32) << 7 | lsb as i32) - 8192;
+                self.bend = 2f32.powf(value as f32 / 8192.0 * BEND_RANGE / 12.0);
+            }
+            (0xb0, cc, value) if cc == self.feedback_cc => self.set_feedback(0.5 + 0.5 * value as f32 / 127.0),
+            _ => {}
+        }
+    }
+
+    pub fn newest(&mut self) -> Option<&mut Voice> {
+        self.voices.iter_mut().filter(|v| v.held).max_by_key(|v| v.started)
+    }
+
+    pub fn tick(&mut self, input: f32, sr: f32) -> f32 {
+        let bend = self.bend;
+        let excitation = self.excitation;
+        let sample = &self.sample;
+        self.voices
+            .iter_mut()
+            .map(|v| {
+                let external = if v.held { input } else { 0.0 };
+                let internal = v.exciter.tick(excitation, sample);
+                v.tick(external + internal, bend, sr)
+            })
+            .sum()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Squared magnitude of the Hann windowed signal at the frequency
+    fn magnitude(signal: &[f32], sr: f32, frequency: f32) -> f64 {
+        let len = signal.len();
+        let w = 2.0 * std::f64::consts::PI * frequency as f64 / sr as f64;
+        let (mut re, mut im) = (0.0, 0.0);
+        for (n, x) in signal.iter().enumerate() {
+            let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * n as f64 / len as f64).cos();
+            re += *x as f64 * window * (w * n as f64).cos();
+            im -= *x as f64 * window * (w * n as f64).sin();
+        }
+        re * re + im * im
+    }
+
+    // Frequency of the strongest component within 50 cents of the expected one, in steps of 0.25 cents
+    fn measured_frequency(signal: &[f32], sr: f32, expected: f32) -> f32 {
+        (-200..=200)
+            .map(|k| expected * 2f32.powf(k as f32 * 0.25 / 1200.0))
+            .max_by(|a, b| magnitude(signal, sr, *a).total_cmp(&magnitude(signal, sr, *b)))
+            .unwrap()
+    }
+
+    fn render(frequency: f32, interpolation: Interpolation, loss: LossFilter) -> Vec<f32> {
+        let sr = 48000.0;
+        let mut voice = Voice::new(LOWEST_FREQUENCY, sr);
+        voice.interpolation = interpolation;
+        voice.loss = loss;
+        voice.start(frequency, 0.999, sr, 1);
+        voice.exciter.trigger(1, 1.0, 0, 0.0);
+        // Skips the excitation so only the string itself is measured
+        (0..14000)
+            .map(|_| {
+                let input = voice.exciter.tick(Excitation::Impulse, &[]);
+                voice.tick(input, 1.0, sr)
+            })
+            .skip(2000)
+            .collect()
+    }
+
+    #[test]
+    fn rendered_pitch_matches_frequency() {
+        for frequency in [110.0, 440.0, 1000.0, 2500.0] {
+            for interpolation in [Interpolation::Linear, Interpolation::Allpass] {
+                let measured = measured_frequency(&render(frequency, interpolation, LossFilter::Average), 48000.0, frequency);
+                let cents = 1200.0 * (measured / frequency).log2();
+                assert!(cents.abs() < 1.0, "{:?} at {} Hz is {} cents off", interpolation, frequency, cents);
+            }
+        }
+    }
+
+    #[test]
+    fn loss_filters_stay_in_tune() {
+        let filters = [
+            LossFilter::OnePole { brightness: 0.5 },
+            LossFilter::ThreeTap { damping: 0.5 },
+            LossFilter::Bands { t60_low: 4.0, t60_high: 0.5 }
+        ];
+        for frequency in [220.0, 1500.0] {
+            for loss in filters {
+                let measured = measured_frequency(&render(frequency, Interpolation::Allpass, loss), 48000.0, frequency);
+                let cents = 1200.0 * (measured / frequency).log2();
+                assert!(cents.abs() < 1.0, "{:?} at {} Hz is {} cents off", loss, frequency, cents);
+            }
+        }
+    }
+
+    #[test]
+    fn bands_decay_the_highs_faster() {
+        let (low, high) = LossFilter::Bands { t60_low: 4.0, t60_high: 1.0 }.one_pole(200.0);
+        // Loop gain at DC and at Nyquist, raised to the loops in the T60 times
+        let gain_pi = low * (1.0 - high) / (1.0 + high);
+        assert!((low.powf(200.0 * 4.0) - 0.001).abs() < 1e-5);
+        assert!((gain_pi.powf(200.0 * 1.0) - 0.001).abs() < 1e-5);
+    }
+
+    #[test]
+    fn pick_filters_shape_the_excitation() {
+        let mut exciter = Exciter::new(64);
+        exciter.trigger(1, 1.0, 10, 0.0);
+        let comb: Vec<f32> = (0..12).map(|_| exciter.tick(Excitation::Impulse, &[])).collect();
+        // The pick position comb adds the inverted reflection one delay later
+        assert_eq!(comb[0], 1.0);
+        assert_eq!(comb[10], -1.0);
+        assert_eq!(comb.iter().map(|x| x.abs()).sum::<f32>(), 2.0);
+
+        exciter.trigger(1, 1.0, 0, 0.5);
+        let down: Vec<f32> = (0..3).map(|_| exciter.tick(Excitation::Impulse, &[])).collect();
+        assert_eq!(down, vec![0.5, 0.25, 0.125]);
+    }
+
+    #[test]
+    fn fundamental_decays_in_t60() {
+        let sr = 48000.0;
+        for (frequency, loss) in [(220.0, LossFilter::Average), (880.0, LossFilter::OnePole { brightness: 0.5 })] {
+            let mut voice = Voice::new(LOWEST_FREQUENCY, sr);
+            voice.loss = loss;
+            voice.t60 = Some(0.5);
+            voice.start(frequency, 1.0, sr, 1);
+            voice.exciter.trigger(1, 1.0, 0, 0.0);
+            let out: Vec<f32> = (0..24000)
+                .map(|_| {
+                    let input = voice.exciter.tick(Excitation::Impulse, &[]);
+                    voice.tick(input, 1.0, sr)
+                })
+                .collect();
+            // A quarter of the T60 apart, so 15 dB down
+            let early = magnitude(&out[2400..7200], sr, frequency);
+            let late = magnitude(&out[8400..13200], sr, frequency);
+            let drop = 10.0 * (early / late).log10();
+            assert!((drop - 15.0).abs() < 1.0, "{} Hz dropped {} dB", frequency, drop);
+        }
+    }
+
+    #[test]
+    fn low_notes_decay_slower_when_stretched() {
+        assert_eq!(decay_time(2.0, 1.0, 880.0), 2.0);
+        assert_eq!(decay_time(2.0, 1.0, 110.0), 8.0);
+        assert_eq!(decay_time(2.0, 0.5, 110.0), 4.0);
+        assert_eq!(decay_time(2.0, 0.0, 110.0), 2.0);
+    }
+
+    #[test]
+    fn glide_time_is_in_milliseconds() {
+        for sr in [44100.0, 96000.0] {
+            let mut linear = Smoother::new(Glide::Linear);
+            linear.jump(100.0);
+            linear.set_time(10.0, sr);
+            let samples = (0.01 * sr) as usize;
+            for _ in 0..samples - 1 {
+                assert!(linear.tick(200.0) < 200.0);
+            }
+            assert_eq!(linear.tick(200.0), 200.0);
+
+            let mut exponential = Smoother::new(Glide::Exponential);
+            exponential.jump(0.0);
+            exponential.set_time(10.0, sr);
+            for _ in 0..samples {
+                exponential.tick(1.0);
+            }
+            assert!((exponential.value - (1.0 - (-1f32).exp())).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn new_notes_glide_from_the_last_one() {
+        let sr = 48000.0;
+        let mut pool = VoicePool::new(2, sr, Stealing::Oldest, 0.99);
+        pool.note_on(200.0, 1.0, sr);
+        pool.note_on(400.0, 1.0, sr);
+        assert_eq!(pool.voices[1].delay.tick(sr / 400.0), sr / 400.0);
+
+        pool.set_glide(50.0, Glide::Linear, sr);
+        pool.note_on(800.0, 1.0, sr);
+        let delay = pool.voices[0].delay.tick(sr / 800.0);
+        assert!(delay > sr / 800.0 && delay < sr / 400.0);
+    }
+
+    #[test]
+    fn delay_line_wraps_around() {
+        let mut line = DelayLine::new(5);
+        for x in 1..=12 {
+            line.write(x as f32);
+        }
+        assert_eq!(line.read(1), 12.0);
+        assert_eq!(line.read(5), 8.0);
+        // Delays outside the line are clamped instead of reading past it
+        assert_eq!(line.read(0), 12.0);
+        assert_eq!(line.read(100), 8.0);
+
+        line.clear();
+        assert_eq!(line.read(3), 0.0);
+    }
+
+    #[test]
+    fn delay_line_interpolates_between_samples() {
+        let mut line = DelayLine::new(8);
+        for x in [0.0, 1.0, 3.0, 7.0] {
+            line.write(x);
+        }
+        assert_eq!(line.read_fractional(1.0), 7.0);
+        assert_eq!(line.read_fractional(1.5), 5.0);
+        assert_eq!(line.read_fractional(2.25), 2.5);
+        assert_eq!(DelayLine::for_frequency(20.0, 48000.0).max_delay(), 2404);
+    }
+
+    #[test]
+    fn released_bands_voice_is_damped() {
+        let sr = 48000.0;
+        let mut pool = VoicePool::new(2, sr, Stealing::Oldest, 1.0);
+        pool.damping = true;
+        pool.excitation = Excitation::Impulse;
+        for voice in pool.voices.iter_mut() {
+            voice.loss = LossFilter::Bands { t60_low: 4.0, t60_high: 1.0 };
+        }
+        pool.note_on(220.0, 1.0, sr);
+        pool.note_on(330.0, 1.0, sr);
+        pool.note_off(330.0);
+        for _ in 0..4800 {
+            pool.tick(0.0, sr);
+        }
+        // Peak of each string over the next few periods
+        let peaks: Vec<f32> = pool.voices
+            .iter_mut()
+            .map(|v| (0..480).map(|_| v.tick(0.0, 1.0, sr).abs()).fold(0.0, f32::max))
+            .collect();
+        assert!(peaks[1] < 0.01 * peaks[0], "released {} held {}", peaks[1], peaks[0]);
+    }
+
+    #[test]
+    fn note_off_releases_a_retuned_note() {
+        let sr = 48000.0;
+        let mut pool = VoicePool::new(2, sr, Stealing::Oldest, 1.0);
+        pool.control(AudioControlMessage::NoteOn(220.0), sr);
+        pool.control(AudioControlMessage::Frequency(247.0), sr);
+        pool.control(AudioControlMessage::NoteOff(220.0), sr);
+        assert!(pool.voices.iter().all(|v| !v.held));
+
+        // The gui releases with the frequency of the slider
+        pool.control(AudioControlMessage::NoteOn(220.0), sr);
+        pool.control(AudioControlMessage::Frequency(247.0), sr);
+        pool.control(AudioControlMessage::NoteOff(247.0), sr);
+        assert!(pool.voices.iter().all(|v| !v.held));
+    }
+}
@@ -1,12 +1,29 @@
-use std::{process};
+use std::{env, fs, process};
 
+use egui::Key;
 use jack::*;
 
-const MAX_DELAY: usize = 1<<24;
+use dsp::*;
 
-enum AudioControlMessage {
-    Feedback(f32),
-    Frequency(f32)
+mod dsp;
+mod render;
+
+// Start up state of the string, shared by the gui and the voices
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    feedback: f32,
+    frequency: f32,
+    stealing: Stealing,
+    damping: bool,
+    excitation: Excitation,
+    interpolation: Interpolation,
+    loss: LossFilter,
+    pick_position: f32,
+    pick_direction: f32,
+    t60: Option<f32>,
+    decay_stretch: f32,
+    glide_time: f32,
+    glide: Glide
 }
 
 struct KpsApp {
@@ -14,28 +31,166 @@ struct KpsApp {
     last_feedback: f32,
     frequency: f32,
     last_frequency: f32,
+    stealing: Stealing,
+    last_stealing: Stealing,
+    damping: bool,
+    last_damping: bool,
+    excitation: Excitation,
+    last_excitation: Excitation,
+    interpolation: Interpolation,
+    last_interpolation: Interpolation,
+    loss: LossFilter,
+    last_loss: LossFilter,
+    pick_position: f32,
+    last_pick_position: f32,
+    pick_direction: f32,
+    last_pick_direction: f32,
+    // Decay in seconds replaces the feedback slider
+    decay_seconds: bool,
+    last_decay_seconds: bool,
+    t60: f32,
+    last_t60: f32,
+    decay_stretch: f32,
+    last_decay_stretch: f32,
+    glide_time: f32,
+    last_glide_time: f32,
+    glide: Glide,
+    last_glide: Glide,
+    // Settings of every loss filter, kept while another one is selected
+    brightness: f32,
+    loss_damping: f32,
+    t60_low: f32,
+    t60_high: f32,
     sender: crossbeam::channel::Sender<AudioControlMessage>
 }
 impl KpsApp {
-    fn new(feedback: f32, frequency: f32, sender: crossbeam::channel::Sender<AudioControlMessage>) -> KpsApp {
-        let app = KpsApp {
+    fn new(settings: Settings, sender: crossbeam::channel::Sender<AudioControlMessage>) -> KpsApp {
+        let Settings { feedback, frequency, stealing, damping, excitation, interpolation, loss, pick_position, pick_direction, t60, decay_stretch, glide_time, glide } = settings;
+        KpsApp {
             feedback,
             last_feedback: feedback,
             frequency,
             last_frequency: frequency,
+            stealing,
+            last_stealing: stealing,
+            damping,
+            last_damping: damping,
+            excitation,
+            last_excitation: excitation,
+            interpolation,
+            last_interpolation: interpolation,
+            loss,
+            last_loss: loss,
+            pick_position,
+            last_pick_position: pick_position,
+            pick_direction,
+            last_pick_direction: pick_direction,
+            decay_seconds: t60.is_some(),
+            last_decay_seconds: t60.is_some(),
+            t60: t60.unwrap_or(2.0),
+            last_t60: t60.unwrap_or(2.0),
+            decay_stretch,
+            last_decay_stretch: decay_stretch,
+            glide_time,
+            last_glide_time: glide_time,
+            glide,
+            last_glide: glide,
+            brightness: match loss {
+                LossFilter::OnePole { brightness } => brightness,
+                _ => 0.5
+            },
+            loss_damping: 0.5,
+            t60_low: 4.0,
+            t60_high: 1.0,
             sender
-        };
-        app
+        }
     }
 }
 impl eframe::App for KpsApp {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-        frame.set_window_size(egui::Vec2 { x: 400.0, y: 400.0 });
+        frame.set_window_size(egui::Vec2 { x: 400.0, y: 560.0 });
 
         egui::CentralPanel::default().show(ctx, |ui| {
 
             ui.add(egui::Slider::new(&mut self.frequency, 130.0..=600.0).text("frequency"));
-            ui.add(egui::Slider::new(&mut self.feedback, 0.5..=1.0).text("feedback"));
+            ui.checkbox(&mut self.decay_seconds, "decay in seconds");
+            if self.decay_seconds {
+                ui.add(egui::Slider::new(&mut self.t60, 0.1..=20.0).logarithmic(true).text("decay T60 (s)"));
+                ui.add(egui::Slider::new(&mut self.decay_stretch, 0.0..=1.0).text("low note stretch"));
+            } else {
+                ui.add(egui::Slider::new(&mut self.feedback, 0.5..=1.0).text("feedback"));
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("excite");
+                ui.radio_value(&mut self.excitation, Excitation::Noise, "noise");
+                ui.radio_value(&mut self.excitation, Excitation::FilteredNoise, "filtered");
+                ui.radio_value(&mut self.excitation, Excitation::Impulse, "impulse");
+                ui.radio_value(&mut self.excitation, Excitation::Sample, "sample");
+            });
+            ui.add(egui::Slider::new(&mut self.pick_position, 0.0..=0.5).text("pick position"));
+            ui.add(egui::Slider::new(&mut self.pick_direction, 0.0..=MAX_PICK_DIRECTION).text("pick direction (up - down)"));
+            ui.horizontal(|ui| {
+                if ui.button("pluck (A)").clicked() || ctx.input().key_pressed(Key::A) {
+                    self.sender.send(AudioControlMessage::Pluck(self.frequency)).unwrap();
+                }
+                if ui.button("note on").clicked() {
+                    self.sender.send(AudioControlMessage::NoteOn(self.frequency)).unwrap();
+                }
+                if ui.button("note off").clicked() {
+                    self.sender.send(AudioControlMessage::NoteOff(self.frequency)).unwrap();
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("glide");
+                ui.radio_value(&mut self.glide, Glide::Linear, "linear");
+                ui.radio_value(&mut self.glide, Glide::Exponential, "exponential");
+            });
+            ui.add(egui::Slider::new(&mut self.glide_time, 0.0..=1000.0).text("glide time (ms)"));
+
+            ui.horizontal(|ui| {
+                ui.label("steal");
+                ui.radio_value(&mut self.stealing, Stealing::Oldest, "oldest");
+                ui.radio_value(&mut self.stealing, Stealing::Quietest, "quietest");
+            });
+            ui.checkbox(&mut self.damping, "damp on note off");
+            ui.horizontal(|ui| {
+                ui.label("tuning");
+                ui.radio_value(&mut self.interpolation, Interpolation::Linear, "linear");
+                ui.radio_value(&mut self.interpolation, Interpolation::Allpass, "allpass");
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("loss");
+                if ui.radio(self.loss == LossFilter::Average, "average").clicked() {
+                    self.loss = LossFilter::Average;
+                }
+                if ui.radio(matches!(self.loss, LossFilter::OnePole { .. }), "one pole").clicked() {
+                    self.loss = LossFilter::OnePole { brightness: self.brightness };
+                }
+                if ui.radio(matches!(self.loss, LossFilter::ThreeTap { .. }), "three tap").clicked() {
+                    self.loss = LossFilter::ThreeTap { damping: self.loss_damping };
+                }
+                if ui.radio(matches!(self.loss, LossFilter::Bands { .. }), "bands").clicked() {
+                    self.loss = LossFilter::Bands { t60_low: self.t60_low, t60_high: self.t60_high };
+                }
+            });
+            match self.loss {
+                LossFilter::Average => {}
+                LossFilter::OnePole { .. } => {
+                    ui.add(egui::Slider::new(&mut self.brightness, 0.01..=1.0).text("brightness"));
+                    self.loss = LossFilter::OnePole { brightness: self.brightness };
+                }
+                LossFilter::ThreeTap { .. } => {
+                    ui.add(egui::Slider::new(&mut self.loss_damping, 0.0..=1.0).text("damping"));
+                    self.loss = LossFilter::ThreeTap { damping: self.loss_damping };
+                }
+                LossFilter::Bands { .. } => {
+                    ui.add(egui::Slider::new(&mut self.t60_low, 0.1..=20.0).logarithmic(true).text("T60 low (s)"));
+                    ui.add(egui::Slider::new(&mut self.t60_high, 0.05..=20.0).logarithmic(true).text("T60 high (s)"));
+                    self.loss = LossFilter::Bands { t60_low: self.t60_low, t60_high: self.t60_high };
+                }
+            }
 
             if self.frequency != self.last_frequency {
                 self.sender.send(AudioControlMessage::Frequency(self.frequency)).unwrap();
@@ -45,33 +200,122 @@ impl eframe::App for KpsApp {
                 self.sender.send(AudioControlMessage::Feedback(self.feedback)).unwrap();
                 self.last_feedback = self.feedback;
             }
+            if self.stealing != self.last_stealing {
+                self.sender.send(AudioControlMessage::Stealing(self.stealing)).unwrap();
+                self.last_stealing = self.stealing;
+            }
+            if self.damping != self.last_damping {
+                self.sender.send(AudioControlMessage::Damping(self.damping)).unwrap();
+                self.last_damping = self.damping;
+            }
+            if self.excitation != self.last_excitation {
+                self.sender.send(AudioControlMessage::Excitation(self.excitation)).unwrap();
+                self.last_excitation = self.excitation;
+            }
+            if self.interpolation != self.last_interpolation {
+                self.sender.send(AudioControlMessage::Interpolation(self.interpolation)).unwrap();
+                self.last_interpolation = self.interpolation;
+            }
+            if self.loss != self.last_loss {
+                self.sender.send(AudioControlMessage::LossFilter(self.loss)).unwrap();
+                self.last_loss = self.loss;
+            }
+            if self.decay_seconds != self.last_decay_seconds || self.t60 != self.last_t60 {
+                let t60 = if self.decay_seconds { Some(self.t60) } else { None };
+                self.sender.send(AudioControlMessage::Decay(t60)).unwrap();
+                // Leaving decay mode restores the feedback of the slider
+                if !self.decay_seconds {
+                    self.sender.send(AudioControlMessage::Feedback(self.feedback)).unwrap();
+                }
+                self.last_decay_seconds = self.decay_seconds;
+                self.last_t60 = self.t60;
+            }
+            if self.decay_stretch != self.last_decay_stretch {
+                self.sender.send(AudioControlMessage::DecayStretch(self.decay_stretch)).unwrap();
+                self.last_decay_stretch = self.decay_stretch;
+            }
+            if self.glide_time != self.last_glide_time {
+                self.sender.send(AudioControlMessage::GlideTime(self.glide_time)).unwrap();
+                self.last_glide_time = self.glide_time;
+            }
+            if self.glide != self.last_glide {
+                self.sender.send(AudioControlMessage::Glide(self.glide)).unwrap();
+                self.last_glide = self.glide;
+            }
+            if self.pick_position != self.last_pick_position {
+                self.sender.send(AudioControlMessage::PickPosition(self.pick_position)).unwrap();
+                self.last_pick_position = self.pick_position;
+            }
+            if self.pick_direction != self.last_pick_direction {
+                self.sender.send(AudioControlMessage::PickDirection(self.pick_direction)).unwrap();
+                self.last_pick_direction = self.pick_direction;
+            }
         });
     }
 }
 
-struct RingBuffer {
-    buffer: Vec<f32>,
-    read_pointer: usize,
-    write_pointer: usize
+// String settings written by `wave-rs kps-fit`, one "key = value" per line
+struct Preset {
+    frequency: f32,
+    feedback: f32,
+    brightness: f32,
+    // Measured decay, the feedback only matches it with the averaging loss filter
+    t60: Option<f32>
 }
 
-impl RingBuffer {
-    fn new() -> RingBuffer {
-        let buffer = vec![0.0; MAX_DELAY];
+impl Preset {
+    fn load(path: &str) -> Preset {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(_) => {
+                panic!("Could not find preset!");
+            }
+        };
 
-        let ring_buffer = RingBuffer { buffer , read_pointer: 0, write_pointer: 0 };
-        ring_buffer
+        let mut preset = Preset { frequency: 220.0, feedback: 0.7, brightness: 1.0, t60: None };
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').expect("Invalid preset line");
+            let value: f32 = value.trim().parse().expect("Invalid preset value");
+            match key.trim() {
+                "frequency" => preset.frequency = value,
+                "feedback" => preset.feedback = value,
+                "brightness" => preset.brightness = value,
+                "t60" => preset.t60 = Some(value),
+                _ => {}
+            }
+        }
+        preset
     }
+}
 
-    fn eval(&self ,pos: f32) -> f32 {
-        let integer = pos.trunc() as usize;
-        let fr = pos.fract();
-
-        let x0 = self.buffer[integer % MAX_DELAY];
-        let x1 = self.buffer[(integer + 1) % MAX_DELAY];
+// First channel of a wav file, played as is whatever its sample rate
+fn load_sample(path: &str) -> Vec<f32> {
+    let reader = match hound::WavReader::open(path) {
+        Ok(reader) => reader,
+        Err(_) => {
+            panic!("Could not find exciter sample!");
+        }
+    };
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.into_samples::<f32>().map(|s| s.unwrap()).collect(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.into_samples::<i32>().map(|s| s.unwrap() as f32 / scale).collect()
+        }
+    };
+    samples.into_iter().step_by(spec.channels.max(1) as usize).collect()
+}
 
-        (1.0-fr)*x0 + fr*x1
-    }
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|val| val.as_str())
 }
 
 struct ShutdownHandler{}
@@ -82,38 +326,29 @@ impl jack::NotificationHandler for ShutdownHandler {
 }
 
 struct KpsEngine {
-    feedback: f32,
-    frequency: f32,
     port_in: jack::Port<AudioIn>,
+    midi_in: jack::Port<MidiIn>,
     port_out: jack::Port<AudioOut>,
     receiver: crossbeam::channel::Receiver<AudioControlMessage>,
-    ring_buffer: RingBuffer,
-    delay_mem: f32,
-    feedback_mem: f32
+    pool: VoicePool
 }
 
 
 impl KpsEngine {
     fn new(
-        feedback: f32,
-        frequency: f32,
+        pool: VoicePool,
         port_in: jack::Port<AudioIn>,
+        midi_in: jack::Port<MidiIn>,
         port_out: jack::Port<AudioOut>,
         receiver: crossbeam::channel::Receiver<AudioControlMessage>,
     ) -> KpsEngine {
-        let ring_buffer = RingBuffer::new();
-
-        let engine = KpsEngine {
-            feedback,
-            frequency,
+        KpsEngine {
             port_in,
+            midi_in,
             port_out,
             receiver,
-            ring_buffer,
-            delay_mem: 0.0,
-            feedback_mem: 0.0,
-        };
-        engine
+            pool
+        }
     }
 
 }
@@ -123,50 +358,21 @@ impl ProcessHandler for KpsEngine {
         client: &jack::Client,
         ps: &jack::ProcessScope
     ) -> Control {
-        let sr = client.sample_rate();
+        let sr = client.sample_rate() as f32;
         let buffer_in = self.port_in.as_slice(ps);
         let buffer_out = self.port_out.as_mut_slice(ps);
 
-        // TODO: receive messages
         while let Ok(command) =  self.receiver.try_recv() {
-            match command {
-                AudioControlMessage::Feedback(val) => {
-                    self.feedback = val;
-                }
-                AudioControlMessage::Frequency(val) => {
-                    self.frequency = val;
-                }
-            }
+            self.pool.control(command, sr);
         }
 
-        let delay = (sr as f32)/self.frequency;
-
+        // MIDI events take effect at their frame within the cycle
+        let mut events = self.midi_in.iter(ps).peekable();
         for (i, o) in buffer_out.iter_mut().enumerate() {
-            self.ring_buffer.read_pointer = self.ring_buffer.write_pointer - self.delay_mem as usize;
-            let mut rp = self.ring_buffer.read_pointer as f32;
-            if rp < 0.0 {
-                rp += MAX_DELAY as f32;
-            }
-            /*println!("read_pointer: {:?}", self.ring_buffer.read_pointer);
-            println!("read_pointer as f32: {:?}", rp);
-            println!("write_pointer : {:?}", self.ring_buffer.write_pointer);
-            println!("");*/
-
-            //delay_tick
-            self.delay_mem = 0.0001* delay + 0.9999 * self.delay_mem;
-            // feedback tick
-            self.feedback_mem = 0.001* self.feedback + 0.999 * self.feedback_mem;
-
-            *o = buffer_in[i] + self.feedback_mem * (
-                0.5*self.ring_buffer.eval(rp)+ 0.5*self.ring_buffer.eval(rp + 1.0)
-            );
-
-            self.ring_buffer.buffer[self.ring_buffer.write_pointer] = *o;
-
-            self.ring_buffer.write_pointer += 1;
-            if self.ring_buffer.write_pointer >= MAX_DELAY {
-                self.ring_buffer.write_pointer -= MAX_DELAY;
+            while let Some(event) = events.next_if(|e| e.time as usize <= i) {
+                self.pool.midi(event.bytes, sr);
             }
+            *o = self.pool.tick(buffer_in[i], sr);
         }
         
         
@@ -174,9 +380,106 @@ impl ProcessHandler for KpsEngine {
     }
 }
 
+// Parses the string flags shared by the live and the offline mode into a pool at the sample rate
+fn string_from_args(args: &[String], sr: f32) -> (VoicePool, Settings) {
+    let preset = match option_value(args, "--preset") {
+        Some(path) => {
+            let preset = Preset::load(path);
+            println!("Preset: {} Hz, feedback {}, brightness {}", preset.frequency, preset.feedback, preset.brightness);
+            preset
+        }
+        None => Preset { frequency: 220.0, feedback: 0.7, brightness: 1.0, t60: None }
+    };
+    let frequency: f32 = option_value(args, "--frequency").map(|f| f.parse().expect("Invalid frequency")).unwrap_or(preset.frequency);
+    let feedback: f32 = option_value(args, "--feedback").map(|f| f.parse().expect("Invalid feedback")).unwrap_or(preset.feedback);
+    let voices: usize = option_value(args, "--voices").map(|v| v.parse().expect("Invalid voice count")).unwrap_or(8);
+    let stealing = match option_value(args, "--steal").unwrap_or("oldest") {
+        "oldest" => Stealing::Oldest,
+        "quietest" => Stealing::Quietest,
+        _ => panic!("Unknown stealing mode")
+    };
+    let damping = args.iter().any(|a| a == "--damp");
+    let sample = option_value(args, "--exciter-sample").map(load_sample).unwrap_or_default();
+    let excitation = match option_value(args, "--excitation") {
+        Some("noise") => Excitation::Noise,
+        Some("filtered") => Excitation::FilteredNoise,
+        Some("impulse") => Excitation::Impulse,
+        Some("sample") => Excitation::Sample,
+        Some(_) => panic!("Unknown excitation"),
+        None if sample.is_empty() => Excitation::Noise,
+        None => Excitation::Sample
+    };
+    let interpolation = match option_value(args, "--interpolation").unwrap_or("allpass") {
+        "linear" => Interpolation::Linear,
+        "allpass" => Interpolation::Allpass,
+        _ => panic!("Unknown interpolation")
+    };
+    // A fitted preset brings its brightness along, the plain string keeps the original averaging
+    let loss = match option_value(args, "--loss") {
+        Some("average") => LossFilter::Average,
+        Some("onepole") => LossFilter::OnePole { brightness: preset.brightness },
+        Some("fir") => LossFilter::ThreeTap { damping: 0.5 },
+        Some("bands") => LossFilter::Bands { t60_low: 4.0, t60_high: 1.0 },
+        Some(_) => panic!("Unknown loss filter"),
+        None if option_value(args, "--preset").is_some() => LossFilter::OnePole { brightness: preset.brightness },
+        None => LossFilter::Average
+    };
+    // A fitted decay time keeps the measured T60 whatever the loss filter takes off the fundamental
+    let t60: Option<f32> = option_value(args, "--decay").map(|t| t.parse().expect("Invalid decay time")).or(preset.t60);
+    let decay_stretch: f32 = option_value(args, "--decay-stretch").map(|s| s.parse().expect("Invalid decay stretch")).unwrap_or(0.0);
+    let glide_time: f32 = option_value(args, "--glide").map(|g| g.parse().expect("Invalid glide time")).unwrap_or(0.0);
+    let glide = match option_value(args, "--glide-mode").unwrap_or("exponential") {
+        "linear" => Glide::Linear,
+        "exponential" => Glide::Exponential,
+        _ => panic!("Unknown glide mode")
+    };
+    let pick_position: f32 = option_value(args, "--pick-position").map(|p| p.parse().expect("Invalid pick position")).unwrap_or(0.0);
+    let pick_direction: f32 = option_value(args, "--pick-direction").map(|p| p.parse().expect("Invalid pick direction")).unwrap_or(0.0);
+
+    let mut pool = VoicePool::new(voices, sr, stealing, if t60.is_some() { 1.0 } else { feedback });
+    pool.damping = damping;
+    pool.excitation = excitation;
+    pool.sample = sample;
+    pool.set_glide(glide_time, glide, sr);
+    pool.pick_position = pick_position;
+    pool.pick_direction = pick_direction;
+    for voice in pool.voices.iter_mut() {
+        voice.interpolation = interpolation;
+        voice.loss = loss;
+        voice.t60 = t60;
+        voice.decay_stretch = decay_stretch;
+    }
+    if let Some(cc) = option_value(args, "--feedback-cc") {
+        pool.feedback_cc = cc.parse().expect("Invalid controller number");
+    }
+
+    let settings = Settings {
+        feedback,
+        frequency,
+        stealing,
+        damping,
+        excitation,
+        interpolation,
+        loss,
+        pick_position,
+        pick_direction,
+        t60,
+        decay_stretch,
+        glide_time,
+        glide
+    };
+    (pool, settings)
+}
+
 fn main() {
     println!("Hello, kps");
 
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).is_some_and(|a| a == "render") {
+        render::run(&args[2..]);
+        return;
+    }
+
     let (client, status) = jack::Client::new(
         "kps",
         jack::ClientOptions::NO_START_SERVER
@@ -187,14 +490,19 @@ fn main() {
         process::exit(1);
     }
 
-    let port_in = client.register_port("in", AudioIn::default()).unwrap();
-    let port_out = client.register_port("out", AudioOut::default()).unwrap();
+    let port_in = client.register_port("in", AudioIn).unwrap();
+    let midi_in = client.register_port("midi_in", MidiIn).unwrap();
+    let port_out = client.register_port("out", AudioOut).unwrap();
 
     let (sender, receiver) = crossbeam::channel::unbounded();
 
+    // The first voice plays the preset, so a patched exciter sounds like the single string did
+    let sr = client.sample_rate() as f32;
+    let (mut pool, settings) = string_from_args(&args, sr);
+    pool.note_on(settings.frequency, 0.0, sr);
 
-    let kps_engine = KpsEngine::new(0.7, 220.0, port_in, port_out, receiver);
-    let kps_app = KpsApp::new(0.7, 220.0, sender);
+    let kps_engine = KpsEngine::new(pool, port_in, midi_in, port_out, receiver);
+    let kps_app = KpsApp::new(settings, sender);
     let sh = ShutdownHandler {};
 
     let client_active = client.activate_async(sh, kps_engine).unwrap();
@@ -203,4 +511,4 @@ fn main() {
     eframe::run_native("KPS", native_options, Box::new(|_| Box::new(kps_app)));
 
     client_active.deactivate().unwrap();
-}
\ No newline at end of file
+}
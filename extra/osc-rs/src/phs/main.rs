@@ -44,10 +44,7 @@ impl jack::ProcessHandler for PhsDriver {
     let freq_buffer = self.freq_out.as_mut_slice(ps);
     let freq_result = self.command_receiver.try_recv();
 
-    let freq = match freq_result {
-        Ok(f) => { f }
-        Err(_) => { 0.0 }
-    };
+    let freq = freq_result.unwrap_or(0.0);
 
     if freq != 0.0 {
       self.last_freq = freq;
@@ -88,11 +85,11 @@ fn main() {
 
   // register output audio port
   let phs_out_port = client
-    .register_port("phs", AudioOut::default())
+    .register_port("phs", AudioOut)
     .unwrap_or_die("fail to register port");
 
   let freq_out_port = client
-    .register_port("freq", AudioOut::default())
+    .register_port("freq", AudioOut)
     .unwrap_or_die("fail to register port");
 
   let (sender, receiver) = crossbeam::channel::unbounded();
@@ -108,7 +105,7 @@ fn main() {
   let phs_app = PhsApp {
     freq: 220.0,
     last_freq: 220.0,
-    sender: sender
+    sender
   };
 
   // activate client
@@ -1,7 +1,7 @@
 use jack::AudioOut;
 use jack::AudioIn;
 use std::f32::consts::TAU;
-use std::{process};
+use std::{env, fs, process};
 
 //const FREQ: f32 = 440.0;
 const AMP: f32 = 0.1;
@@ -145,8 +145,7 @@ fn interpolate(v00: f32, v10: f32, v01: f32, v11: f32, i_fr: f32, j_fr: f32) ->
   let u0 = (1.0 - i_fr)* v00 + i_fr*v10;
   let u1 = (1.0 - i_fr)*v01 + i_fr*v11;
 
-  let out = (1.0-j_fr)*u0 + j_fr*u1;
-  out
+  (1.0-j_fr)*u0 + j_fr*u1
 }
 
 struct WaveTable {
@@ -154,24 +153,40 @@ struct WaveTable {
 }
 
 impl WaveTable {
-  fn new() -> WaveTable {
-    let step = 1.0 / TBL_LEN as f32;
+  // Reads one table of a wave-rs wavetable file (one cycle per line, comma separated),
+  // resampled to TBL_LEN
+  fn load(file_name: &str, index: usize) -> WaveTable {
+    let text = fs::read_to_string(file_name).unwrap_or_die("fail to read wavetable");
+    let line = text
+      .lines()
+      .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+      .nth(index)
+      .unwrap_or_else(|| die("wavetable index out of range"));
+    let cycle: Vec<f32> = line
+      .split(',')
+      .map(|v| v.trim().parse().unwrap_or_die("invalid wavetable value"))
+      .collect();
 
     let mut table = WaveTable {
       tbl: vec![]
     };
-    
+
     for i in 0..TBL_LEN {
-      table.tbl.push(TAU * (i as f32) * step)
+      let pos = i as f32 * cycle.len() as f32 / TBL_LEN as f32;
+      let integer = pos.floor() as usize;
+      let fractional = pos - integer as f32;
+      let x0 = cycle[integer];
+      let x1 = cycle[(integer + 1) % cycle.len()];
+      table.tbl.push((1.0 - fractional)*x0 + fractional*x1)
     }
     table
   }
 
   fn eval(&self, phs: f32) -> f32 {
-    let mut fractional = phs  * (TBL_LEN as f32);
-    let integer = fractional.floor() as usize;
+    let mut fractional = (phs - phs.floor()) * (TBL_LEN as f32);
+    let integer = fractional.floor() as usize % TBL_LEN;
 
-    fractional = fractional - integer as f32;
+    fractional -= fractional.floor();
 
     let x0 = self.tbl[integer];
     let x1 = self.tbl[(integer + 1) % TBL_LEN];
@@ -183,12 +198,13 @@ impl WaveTable {
 
 struct OscDriver {
   terrain: WaveTerrain,
-  table: WaveTable,
+  // Plays the wavetable instead of the terrain when one was loaded
+  table: Option<WaveTable>,
   phs: f32,
   mod_freq: f32,
   mod_phs: f32,
   phs_in: jack::Port<AudioIn>,
-  freq_in: jack::Port<AudioIn>,
+  _freq_in: jack::Port<AudioIn>,
   out: jack::Port<AudioOut>,
   receiver: crossbeam::channel::Receiver<Message>
 }
@@ -221,9 +237,14 @@ impl jack::ProcessHandler for OscDriver {
     for (i, o) in out.iter_mut().enumerate() {
       //self.terrain.radius += 0.1*((TAU * self.mod_phs).sin());
       self.phs = phs_buffer[i];
-      let x = self.terrain.radius * (self.phs * TAU).cos() + self.terrain.center_x;
-      let y = self.terrain.radius * (self.phs * TAU).sin() + self.terrain.center_y;
-      *o = AMP * self.terrain.eval(x, y);
+      *o = match &self.table {
+        Some(table) => AMP * table.eval(self.phs),
+        None => {
+          let x = self.terrain.radius * (self.phs * TAU).cos() + self.terrain.center_x;
+          let y = self.terrain.radius * (self.phs * TAU).sin() + self.terrain.center_y;
+          AMP * self.terrain.eval(x, y)
+        }
+      };
 
       self.mod_phs += self.mod_freq/sr;
       while self.mod_phs >= 1.0 {
@@ -243,8 +264,13 @@ impl jack::NotificationHandler for ShutdownHandler {
 }
 
 fn main() {
-  // initialize table
-  let tbl = WaveTable::new();
+  // initialize table, from a file written by `wave-rs wavetable` if given
+  let args: Vec<String> = env::args().collect();
+  let option = |name: &str| args.iter().position(|a| a == name).and_then(|i| args.get(i + 1));
+  let tbl = option("--table").map(|file| {
+    let index = option("--table-index").map(|i| i.parse().unwrap_or_die("invalid table index")).unwrap_or(0);
+    WaveTable::load(file, index)
+  });
   let terrain = WaveTerrain::new();
 
   // open client
@@ -263,14 +289,14 @@ fn main() {
 
   // register output audio port
   let port_out = client
-    .register_port("out", AudioOut::default())
+    .register_port("out", AudioOut)
     .unwrap_or_die("fail to register port");
 
   let phs_in_port = client
-        .register_port("phs", AudioIn::default())
+        .register_port("phs", AudioIn)
         .unwrap_or_die("fail to register poirt");
   let freq_in_port = client
-        .register_port("freq", AudioIn::default())
+        .register_port("freq", AudioIn)
         .unwrap_or_die("fail to register poirt");
   // activate client
   let osc_driver = OscDriver {
@@ -280,7 +306,7 @@ fn main() {
     mod_freq: 3.0,
     mod_phs: 0.0,
     phs_in: phs_in_port,
-    freq_in: freq_in_port,
+    _freq_in: freq_in_port,
     out: port_out,
     receiver
   };
@@ -329,7 +355,7 @@ impl<T, E> UnwrapDie<T> for Result<T, E> {
 
 #[cfg(test)]
 mod tests {
-    use crate::index_and_fractional;
+    use crate::{index_and_fractional, WaveTable, TBL_LEN};
 
   #[test]
   fn indexes_1() {
@@ -350,4 +376,11 @@ mod tests {
     println!("index: {:?}, fractional: {:?}", index, fractional);
     assert!(index < 256)
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn table_wraps_phase() {
+    let table = WaveTable { tbl: (0..TBL_LEN).map(|i| i as f32).collect() };
+    assert_eq!(table.eval(0.5), table.eval(1.5));
+    assert_eq!(table.eval(-0.25), table.eval(0.75));
+  }
+}
@@ -0,0 +1,319 @@
+use std::{fs::File, io::Write, process};
+
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+
+use crate::{amdf_curve, asdf_curve, detect_frequency_amdf, detect_frequency_asdf, decode::RawFormat, envelope::{envelope, envelope_stream, Envelope}, lpc::{self, formant_track, formant_track_stream, Formant}, option_value, read_audio, Estimate, smooth::{self, Candidate}, spectral, stream::WavStream};
+
+// Estimates outside this range are treated as unvoiced
+pub const MIN_FREQUENCY: f64 = 40.0;
+pub const MAX_FREQUENCY: f64 = 2000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Amdf,
+    Asdf,
+    Cepstrum,
+    Hps
+}
+
+impl Method {
+    pub fn from_name(name: &str) -> Option<Method> {
+        match name {
+            "amdf" => Some(Method::Amdf),
+            "asdf" => Some(Method::Asdf),
+            "cepstrum" => Some(Method::Cepstrum),
+            "hps" => Some(Method::Hps),
+            _ => None
+        }
+    }
+
+    pub fn detect(&self, data: &[i16], sample_rate: u32) -> Estimate {
+        match self {
+            Method::Amdf => detect_frequency_amdf(data, sample_rate),
+            Method::Asdf => detect_frequency_asdf(data, sample_rate),
+            Method::Cepstrum => spectral::detect_frequency_cepstrum(data, sample_rate),
+            Method::Hps => spectral::detect_frequency_hps(data, sample_rate)
+        }
+    }
+
+    // Difference function over lag, the frequency domain methods have none
+    pub fn curve(&self, data: &[i16]) -> Option<Vec<f64>> {
+        match self {
+            Method::Amdf => Some(amdf_curve(data)),
+            Method::Asdf => Some(asdf_curve(data)),
+            Method::Cepstrum | Method::Hps => None
+        }
+    }
+}
+
+// Frame-wise frequency estimates, 0.0 marks frames without a period
+pub struct Contour {
+    pub frame_len: usize,
+    pub hop: usize,
+    pub frequencies: Vec<f64>,
+    // Confidence of the estimate behind each frequency, also kept for frames that were dropped
+    pub confidences: Vec<f64>
+}
+
+impl Contour {
+    // Estimate of the frame whose center is closest to the sample position
+    pub fn frequency_at(&self, sample: usize) -> f64 {
+        if self.frequencies.is_empty() {
+            return 0.0;
+        }
+        let offset = sample.saturating_sub(self.frame_len / 2) + self.hop / 2;
+        let frame = (offset / self.hop).min(self.frequencies.len() - 1);
+        self.frequencies[frame]
+    }
+}
+
+pub enum Smoothing {
+    None,
+    Median(usize),
+    Viterbi(usize)
+}
+
+// Number of frames analyzed per block when streaming
+pub const BLOCK_FRAMES: usize = 256;
+
+pub struct ContourOptions {
+    pub method: Method,
+    pub frame_len: usize,
+    pub hop: usize,
+    pub smoothing: Smoothing,
+    // 0 uses all cores, 1 runs sequentially
+    pub threads: usize,
+    // Estimates below this confidence are treated as unvoiced
+    pub min_confidence: f64
+}
+
+impl ContourOptions {
+    // Framing, method and smoothing selected by the command line flags
+    pub fn from_args(args: &[String], sample_rate: u32) -> ContourOptions {
+        let method = match option_value(args, "--method") {
+            Some(name) => Method::from_name(name).expect("Unknown method"),
+            None => Method::Amdf
+        };
+        let smoothing = match option_value(args, "--smooth").unwrap_or("none") {
+            "none" => Smoothing::None,
+            "median" => Smoothing::Median(
+                option_value(args, "--median-width").map(|w| w.parse().expect("Invalid median width")).unwrap_or(5)
+            ),
+            "viterbi" => Smoothing::Viterbi(
+                option_value(args, "--candidates").map(|c| c.parse().expect("Invalid candidate count")).unwrap_or(5)
+            ),
+            _ => panic!("Unknown smoothing")
+        };
+        let threads = option_value(args, "--threads").map(|t| t.parse().expect("Invalid thread count")).unwrap_or(0);
+        let min_confidence = option_value(args, "--min-confidence").map(|c| c.parse().expect("Invalid confidence")).unwrap_or(0.0);
+
+        // Very low raw rates would give empty frames and a hop that never advances
+        ContourOptions {
+            method,
+            frame_len: ((sample_rate as usize)/1000 * 50).max(2),
+            hop: ((sample_rate as usize)/100).max(1),
+            smoothing,
+            threads,
+            min_confidence
+        }
+    }
+
+    fn frame_candidates(&self, frame: &[i16], sample_rate: u32) -> Vec<Candidate> {
+        match self.smoothing {
+            Smoothing::Viterbi(count) => smooth::candidates(frame, sample_rate, self.method, count, self.min_confidence),
+            _ => {
+                let estimate = self.method.detect(frame, sample_rate);
+                let mut frequency = estimate.frequency;
+                if !(MIN_FREQUENCY..=MAX_FREQUENCY).contains(&frequency) || estimate.confidence < self.min_confidence {
+                    frequency = 0.0;
+                }
+                vec![Candidate { frequency, cost: 0.0, confidence: estimate.confidence }]
+            }
+        }
+    }
+
+    // Analyzes every complete frame in data, returns the number of frames
+    fn analyze(&self, data: &[i16], sample_rate: u32, pool: &Option<ThreadPool>, frames: &mut Vec<Vec<Candidate>>) -> usize {
+        let mut starts: Vec<usize> = Vec::new();
+        let mut start = 0;
+        while start + self.frame_len <= data.len() {
+            starts.push(start);
+            start += self.hop;
+        }
+
+        let analyze_frame = |start: &usize| self.frame_candidates(&data[*start..*start + self.frame_len], sample_rate);
+        match pool {
+            Some(pool) => frames.extend(pool.install(|| starts.par_iter().map(analyze_frame).collect::<Vec<_>>())),
+            None => frames.extend(starts.iter().map(analyze_frame))
+        }
+        starts.len()
+    }
+
+    fn pool(&self) -> Option<ThreadPool> {
+        if self.threads == 1 {
+            return None;
+        }
+        Some(ThreadPoolBuilder::new().num_threads(self.threads).build().unwrap())
+    }
+
+    fn finish(&self, frames: Vec<Vec<Candidate>>) -> Contour {
+        let estimates: Vec<Estimate> = match self.smoothing {
+            Smoothing::Viterbi(_) => smooth::viterbi(&frames),
+            _ => frames.iter().map(|c| c[0].estimate()).collect()
+        };
+        let mut frequencies: Vec<f64> = estimates.iter().map(|e| e.frequency).collect();
+        if let Smoothing::Median(width) = self.smoothing {
+            frequencies = smooth::median_filter(&frequencies, width);
+        }
+        Contour {
+            frame_len: self.frame_len,
+            hop: self.hop,
+            frequencies,
+            confidences: estimates.iter().map(|e| e.confidence).collect()
+        }
+    }
+
+    pub fn contour(&self, data: &[i16], sample_rate: u32) -> Contour {
+        let mut frames: Vec<Vec<Candidate>> = Vec::new();
+        self.analyze(data, sample_rate, &self.pool(), &mut frames);
+        self.finish(frames)
+    }
+
+    // Same result as contour, but only keeps one block of samples in memory
+    pub fn contour_stream(&self, stream: &mut WavStream) -> Contour {
+        let sample_rate = stream.header.sampling_rate;
+        let pool = self.pool();
+        let mut frames: Vec<Vec<Candidate>> = Vec::new();
+        let mut buffer: Vec<i16> = Vec::new();
+
+        loop {
+            let read = stream.read(&mut buffer, BLOCK_FRAMES * self.hop);
+            let count = self.analyze(&buffer, sample_rate, &pool, &mut frames);
+            buffer.drain(..(count * self.hop).min(buffer.len()));
+            if read == 0 {
+                break;
+            }
+        }
+        self.finish(frames)
+    }
+}
+
+pub fn run(args: &[String]) {
+    if args.len() < 2 {
+        eprintln!("Usage: wave-rs contour <in.wav> <out.csv> [--method amdf|asdf|cepstrum|hps] [--smooth none|median|viterbi] [--median-width <frames>] [--candidates <count>] [--threads <count>] [--min-confidence <0-1>] [--confidence] [--levels] [--formants <count>] [--order <lpc order>] [--raw-format <format> --raw-rate <hz> --raw-channels <n>]");
+        process::exit(1);
+    }
+
+    // 16 bit wav files are streamed, everything else is decoded as a whole
+    let levels = args.iter().any(|a| a == "--levels");
+    let confidence = args.iter().any(|a| a == "--confidence");
+    let formant_count: Option<usize> = option_value(args, "--formants").map(|c| c.parse().expect("Invalid formant count"));
+    let order = |sample_rate: u32| option_value(args, "--order").map(|o| o.parse().expect("Invalid order")).unwrap_or(lpc::default_order(sample_rate));
+    let stream = match RawFormat::from_args(args) {
+        Some(_) => None,
+        None => WavStream::open(&args[0])
+    };
+    let (sample_rate, contour, envelope, formants): (u32, Contour, Option<Envelope>, Option<Vec<Vec<Formant>>>) = match stream {
+        Some(mut stream) => {
+            let sample_rate = stream.header.sampling_rate;
+            let options = ContourOptions::from_args(args, sample_rate);
+            let contour = options.contour_stream(&mut stream);
+            let envelope = if levels {
+                Some(envelope_stream(&mut WavStream::open(&args[0]).unwrap(), options.frame_len, options.hop))
+            } else {
+                None
+            };
+            let formants = formant_count.map(|_| {
+                formant_track_stream(&mut WavStream::open(&args[0]).unwrap(), options.frame_len, options.hop, order(sample_rate))
+            });
+            (sample_rate, contour, envelope, formants)
+        }
+        None => {
+            let (sample_rate, samples) = read_audio(&args[0], args);
+            let options = ContourOptions::from_args(args, sample_rate);
+            let contour = options.contour(&samples, sample_rate);
+            let envelope = if levels {
+                Some(envelope(&samples, sample_rate, options.frame_len, options.hop))
+            } else {
+                None
+            };
+            let formants = formant_count.map(|_| formant_track(&samples, sample_rate, options.frame_len, options.hop, order(sample_rate)));
+            (sample_rate, contour, envelope, formants)
+        }
+    };
+
+    // RMS, peak and crest factor in dB per frame, appended as extra columns
+    if let Some(envelope) = &envelope {
+        match envelope.loudness {
+            Some(lufs) => println!("Integrated Loudness: {:.2} LUFS", lufs),
+            None => println!("Integrated Loudness: silent")
+        }
+    }
+
+    let mut file = File::create(&args[1]).unwrap();
+    for (i, f) in contour.frequencies.iter().enumerate() {
+        let time = (i * contour.hop + contour.frame_len / 2) as f64 / sample_rate as f64;
+        write!(file, "{:.4},{:.2}", time, f).unwrap();
+        if confidence {
+            write!(file, ",{:.3}", contour.confidences[i]).unwrap();
+        }
+        if let Some(envelope) = &envelope {
+            let level = envelope.levels[i];
+            write!(file, ",{:.2},{:.2},{:.2}", level.rms_db(), level.peak_db(), level.crest_db()).unwrap();
+        }
+        // Frequency and bandwidth of the lowest formants, 0.00 where the frame has fewer
+        if let (Some(formants), Some(count)) = (&formants, formant_count) {
+            for k in 0..count {
+                let formant = formants[i].get(k).copied().unwrap_or(Formant { frequency: 0.0, bandwidth: 0.0 });
+                write!(file, ",{:.2},{:.2}", formant.frequency, formant.bandwidth).unwrap();
+            }
+        }
+        writeln!(file).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{synth::{synthesize, SynthParams, Waveform}, write_wav};
+
+    #[test]
+    fn parallel_and_streamed_contours_match_sequential() {
+        // Long enough for several stream blocks at this rate, with vibrato and noise so frames differ
+        let sample_rate = 8000;
+        let params = SynthParams {
+            waveform: Waveform::Saw,
+            frequency: 180.0,
+            duration: 6.0,
+            sample_rate,
+            amplitude: 0.5,
+            noise: 0.05,
+            vibrato_rate: 3.0,
+            vibrato_depth: 80.0,
+            seed: 3
+        };
+        let data: Vec<i16> = synthesize(&params).iter().map(|s| (s * 32767.0).round() as i16).collect();
+        let file = std::env::temp_dir().join(format!("wave-rs-contour-paths-{}.wav", std::process::id()));
+        let file = file.to_str().unwrap();
+        write_wav(file, sample_rate, data.clone());
+
+        for smoothing in [Smoothing::Viterbi(5), Smoothing::Median(5)] {
+            let mut options = ContourOptions::from_args(&[], sample_rate);
+            options.smoothing = smoothing;
+            assert!(data.len() > 2 * BLOCK_FRAMES * options.hop);
+
+            options.threads = 1;
+            let sequential = options.contour(&data, sample_rate);
+            assert!(sequential.frequencies.iter().filter(|f| **f > 0.0).count() > sequential.frequencies.len() / 2);
+            options.threads = 0;
+            let parallel = options.contour(&data, sample_rate);
+            let streamed = options.contour_stream(&mut WavStream::open(file).unwrap());
+
+            assert_eq!(sequential.frequencies, parallel.frequencies);
+            assert_eq!(sequential.confidences, parallel.confidences);
+            assert_eq!(sequential.frequencies, streamed.frequencies);
+            assert_eq!(sequential.confidences, streamed.confidences);
+        }
+        std::fs::remove_file(file).unwrap();
+    }
+}
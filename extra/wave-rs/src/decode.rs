@@ -0,0 +1,248 @@
+use std::{fs::{self, File}, path::Path};
+
+use crate::option_value;
+
+// Interleaved samples of any input format, normalized to [-1, 1]
+pub struct Decoded {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub samples: Vec<f64>
+}
+
+impl Decoded {
+    // First channel in the 16 bit range the detectors work on
+    pub fn first_channel_i16(&self) -> Vec<i16> {
+        self.samples
+            .iter()
+            .step_by(self.channels.max(1))
+            .map(|s| (s * 32768.0).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    U8,
+    S16,
+    S24,
+    S32,
+    F32
+}
+
+impl SampleFormat {
+    fn bytes(&self) -> usize {
+        match self {
+            SampleFormat::U8 => 1,
+            SampleFormat::S16 => 2,
+            SampleFormat::S24 => 3,
+            SampleFormat::S32 | SampleFormat::F32 => 4
+        }
+    }
+
+    // Normalized value of one sample stored in big endian byte order
+    fn decode_be(&self, b: &[u8]) -> f64 {
+        match self {
+            SampleFormat::U8 => (b[0] as f64 - 128.0) / 128.0,
+            SampleFormat::S16 => i16::from_be_bytes([b[0], b[1]]) as f64 / 32768.0,
+            SampleFormat::S24 => (i32::from_be_bytes([b[0], b[1], b[2], 0]) >> 8) as f64 / 8388608.0,
+            SampleFormat::S32 => i32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64 / 2147483648.0,
+            SampleFormat::F32 => f32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64
+        }
+    }
+}
+
+// Layout of headerless input, given on the command line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RawFormat {
+    pub sample_rate: u32,
+    pub format: SampleFormat,
+    pub big_endian: bool,
+    pub channels: usize
+}
+
+impl RawFormat {
+    // Reads --raw-rate, --raw-format (u8, s16le, s24be, f32le, ...) and --raw-channels
+    pub fn from_args(args: &[String]) -> Option<RawFormat> {
+        let name = option_value(args, "--raw-format")?;
+        let (format, big_endian) = match name {
+            "u8" => (SampleFormat::U8, false),
+            "s16le" => (SampleFormat::S16, false),
+            "s16be" => (SampleFormat::S16, true),
+            "s24le" => (SampleFormat::S24, false),
+            "s24be" => (SampleFormat::S24, true),
+            "s32le" => (SampleFormat::S32, false),
+            "s32be" => (SampleFormat::S32, true),
+            "f32le" => (SampleFormat::F32, false),
+            "f32be" => (SampleFormat::F32, true),
+            _ => panic!("Unknown raw format!")
+        };
+        let sample_rate = option_value(args, "--raw-rate").map(|r| r.parse().expect("Invalid raw rate")).unwrap_or(44100);
+        if sample_rate == 0 {
+            panic!("Raw rate must be positive!");
+        }
+        Some(RawFormat {
+            sample_rate,
+            format,
+            big_endian,
+            channels: option_value(args, "--raw-channels").map(|c| c.parse().expect("Invalid raw channels")).unwrap_or(1)
+        })
+    }
+}
+
+fn decode_pcm(data: &[u8], format: SampleFormat, big_endian: bool) -> Vec<f64> {
+    let mut be = vec![0u8; format.bytes()];
+    data.chunks_exact(format.bytes())
+        .map(|b| {
+            be.copy_from_slice(b);
+            if !big_endian {
+                be.reverse();
+            }
+            format.decode_be(&be)
+        })
+        .collect()
+}
+
+pub fn decode_raw(data: &[u8], raw: RawFormat) -> Decoded {
+    Decoded {
+        sample_rate: raw.sample_rate,
+        channels: raw.channels,
+        samples: decode_pcm(data, raw.format, raw.big_endian)
+    }
+}
+
+pub fn decode_wav(file: &mut File) -> Decoded {
+    let (header, data) = wav::read(file).unwrap();
+    let samples = match data {
+        wav::BitDepth::Eight(v) => v.iter().map(|s| (*s as f64 - 128.0) / 128.0).collect(),
+        wav::BitDepth::Sixteen(v) => v.iter().map(|s| *s as f64 / 32768.0).collect(),
+        // The wav crate keeps 24 bit samples in the upper bytes of an i32
+        wav::BitDepth::TwentyFour(v) => v.iter().map(|s| *s as f64 / 2147483648.0).collect(),
+        wav::BitDepth::ThirtyTwoFloat(v) => v.iter().map(|s| *s as f64).collect(),
+        wav::BitDepth::Empty => Vec::new()
+    };
+    Decoded { sample_rate: header.sampling_rate, channels: header.channel_count as usize, samples }
+}
+
+pub fn decode_flac(file: File) -> Decoded {
+    let mut reader = claxon::FlacReader::new(file).expect("Invalid flac file!");
+    let info = reader.streaminfo();
+    let scale = (1u64 << (info.bits_per_sample - 1)) as f64;
+    let samples = reader.samples().map(|s| s.unwrap() as f64 / scale).collect();
+    Decoded { sample_rate: info.sample_rate, channels: info.channels as usize, samples }
+}
+
+// 80 bit IEEE extended float, used for the AIFF sample rate
+fn extended_to_f64(b: &[u8]) -> f64 {
+    let exponent = (((b[0] & 0x7f) as i32) << 8) | b[1] as i32;
+    let mantissa = u64::from_be_bytes([b[2], b[3], b[4], b[5], b[6], b[7], b[8], b[9]]);
+    if exponent == 0 && mantissa == 0 {
+        return 0.0;
+    }
+    let value = mantissa as f64 * 2f64.powi(exponent - 16383 - 63);
+    if b[0] & 0x80 != 0 { -value } else { value }
+}
+
+pub fn decode_aiff(data: &[u8]) -> Decoded {
+    if data.len() < 12 || &data[0..4] != b"FORM" || !(&data[8..12] == b"AIFF" || &data[8..12] == b"AIFC") {
+        panic!("Not an aiff file!");
+    }
+    let be_u32 = |b: &[u8]| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+
+    let mut channels = 0;
+    let mut sample_rate = 0.0;
+    let mut format = SampleFormat::S16;
+    let mut big_endian = true;
+    let mut sound: &[u8] = &[];
+
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let size = be_u32(&data[pos + 4..]);
+        let body = &data[pos + 8..(pos + 8 + size).min(data.len())];
+        match &data[pos..pos + 4] {
+            b"COMM" => {
+                channels = i16::from_be_bytes([body[0], body[1]]) as usize;
+                let bits = i16::from_be_bytes([body[6], body[7]]);
+                sample_rate = extended_to_f64(&body[8..18]);
+                format = match bits {
+                    1..=8 => SampleFormat::U8,
+                    9..=16 => SampleFormat::S16,
+                    17..=24 => SampleFormat::S24,
+                    _ => SampleFormat::S32
+                };
+                // AIFC names its encoding after the common fields
+                if body.len() >= 22 {
+                    match &body[18..22] {
+                        b"NONE" | b"twos" => {}
+                        b"sowt" => big_endian = false,
+                        b"fl32" | b"FL32" => format = SampleFormat::F32,
+                        _ => panic!("Compressed aiff is not supported!")
+                    }
+                }
+            }
+            b"SSND" => {
+                let offset = be_u32(body);
+                sound = &body[(8 + offset).min(body.len())..];
+            }
+            _ => {}
+        }
+        pos += 8 + size + size % 2;
+    }
+
+    // AIFF stores 8 bit samples signed
+    let samples = if format == SampleFormat::U8 {
+        sound.iter().map(|s| *s as i8 as f64 / 128.0).collect()
+    } else {
+        decode_pcm(sound, format, big_endian)
+    };
+    Decoded { sample_rate: sample_rate.round() as u32, channels, samples }
+}
+
+// Picks the decoder from the raw flags or the file extension
+pub fn decode(file_name: &str, raw: Option<RawFormat>) -> Decoded {
+    let file = match File::open(file_name) {
+        Ok(file) => file,
+        Err(_) => {
+            panic!("Could not find file!");
+        }
+    };
+    if let Some(raw) = raw {
+        return decode_raw(&fs::read(file_name).unwrap(), raw);
+    }
+
+    let extension = Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    match extension.as_str() {
+        "flac" => decode_flac(file),
+        "aif" | "aiff" | "aifc" => decode_aiff(&fs::read(file_name).unwrap()),
+        "raw" | "pcm" => panic!("Raw input needs --raw-format!"),
+        _ => {
+            let mut file = file;
+            decode_wav(&mut file)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_sample_rate() {
+        let rate = [0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0];
+        assert_eq!(extended_to_f64(&rate), 44100.0);
+    }
+
+    #[test]
+    fn raw_formats_share_normalization() {
+        let le = decode_pcm(&[0x00, 0x40, 0x00, 0xc0], SampleFormat::S16, false);
+        let be = decode_pcm(&[0x40, 0x00, 0xc0, 0x00], SampleFormat::S16, true);
+        assert_eq!(le, vec![0.5, -0.5]);
+        assert_eq!(le, be);
+
+        let s24 = decode_pcm(&[0x00, 0x00, 0x40], SampleFormat::S24, false);
+        assert_eq!(s24, vec![0.5]);
+    }
+}
@@ -0,0 +1,253 @@
+use std::f64::consts::PI;
+
+use crate::{contour::BLOCK_FRAMES, stream::WavStream};
+
+// Levels below this are reported as silence
+const FLOOR_DB: f64 = -120.0;
+// Loudness gating as in ITU-R BS.1770, blocks of 400 ms with 75 % overlap
+const GATE_BLOCK: f64 = 0.4;
+const GATE_STEP: f64 = 0.1;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+
+pub fn to_db(value: f64) -> f64 {
+    if value <= 0.0 {
+        return FLOOR_DB;
+    }
+    (20.0 * value.log10()).max(FLOOR_DB)
+}
+
+// Per frame levels relative to full scale
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Level {
+    pub rms: f64,
+    pub peak: f64
+}
+
+impl Level {
+    pub fn of(frame: &[i16]) -> Level {
+        let mut sum = 0.0;
+        let mut peak: f64 = 0.0;
+        for s in frame {
+            let x = *s as f64 / 32768.0;
+            sum += x * x;
+            peak = peak.max(x.abs());
+        }
+        Level { rms: (sum / frame.len().max(1) as f64).sqrt(), peak }
+    }
+
+    pub fn rms_db(&self) -> f64 {
+        to_db(self.rms)
+    }
+
+    pub fn peak_db(&self) -> f64 {
+        to_db(self.peak)
+    }
+
+    pub fn crest_db(&self) -> f64 {
+        self.peak_db() - self.rms_db()
+    }
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2]
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Biquad {
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            x: [0.0; 2],
+            y: [0.0; 2]
+        }
+    }
+
+    fn tick(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// K-weighting followed by gated block energies, fed sample by sample
+pub struct LoudnessMeter {
+    shelf: Biquad,
+    highpass: Biquad,
+    block_len: usize,
+    step: usize,
+    // Energy of the steps making up the current block
+    steps: Vec<f64>,
+    step_sum: f64,
+    step_count: usize,
+    blocks: Vec<f64>
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32) -> LoudnessMeter {
+        let sr = sample_rate as f64;
+
+        // High shelf of +4 dB at 1500 Hz
+        let a = 10f64.powf(4.0 / 40.0);
+        let w0 = 2.0 * PI * 1500.0 / sr;
+        let alpha = w0.sin() / (2.0 * f64::sqrt(0.5));
+        let cos = w0.cos();
+        let shelf = Biquad::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + 2.0 * a.sqrt() * alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - 2.0 * a.sqrt() * alpha)
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + 2.0 * a.sqrt() * alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - 2.0 * a.sqrt() * alpha
+            ]
+        );
+
+        // High pass at 38 Hz
+        let w0 = 2.0 * PI * 38.0 / sr;
+        let alpha = w0.sin() / (2.0 * 0.5);
+        let cos = w0.cos();
+        let highpass = Biquad::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha]
+        );
+
+        let step = (GATE_STEP * sr) as usize;
+        LoudnessMeter {
+            shelf,
+            highpass,
+            block_len: (GATE_BLOCK / GATE_STEP).round() as usize,
+            step,
+            steps: Vec::new(),
+            step_sum: 0.0,
+            step_count: 0,
+            blocks: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, samples: &[i16]) {
+        for s in samples {
+            let y = self.highpass.tick(self.shelf.tick(*s as f64 / 32768.0));
+            self.step_sum += y * y;
+            self.step_count += 1;
+
+            if self.step_count == self.step {
+                self.steps.push(self.step_sum / self.step as f64);
+                self.step_sum = 0.0;
+                self.step_count = 0;
+                if self.steps.len() >= self.block_len {
+                    let block = &self.steps[self.steps.len() - self.block_len..];
+                    self.blocks.push(block.iter().sum::<f64>() / self.block_len as f64);
+                }
+            }
+        }
+    }
+
+    // Integrated loudness in LUFS, None if every block was gated away
+    pub fn integrated(&self) -> Option<f64> {
+        let loudness = |energy: f64| -0.691 + 10.0 * energy.log10();
+        let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+        let audible: Vec<f64> = self.blocks.iter().copied().filter(|e| *e > 0.0 && loudness(*e) > ABSOLUTE_GATE).collect();
+        if audible.is_empty() {
+            return None;
+        }
+        let threshold = loudness(mean(&audible)) + RELATIVE_GATE;
+        let gated: Vec<f64> = audible.into_iter().filter(|e| loudness(*e) > threshold).collect();
+        Some(loudness(mean(&gated)))
+    }
+}
+
+pub struct Envelope {
+    pub levels: Vec<Level>,
+    pub loudness: Option<f64>
+}
+
+// Levels with the framing of the pitch contour, plus the loudness of the whole file
+pub fn envelope(data: &[i16], sample_rate: u32, frame_len: usize, hop: usize) -> Envelope {
+    let mut meter = LoudnessMeter::new(sample_rate);
+    meter.push(data);
+
+    let mut levels: Vec<Level> = Vec::new();
+    let mut pos = 0;
+    while pos + frame_len <= data.len() {
+        levels.push(Level::of(&data[pos..pos + frame_len]));
+        pos += hop;
+    }
+
+    Envelope { levels, loudness: meter.integrated() }
+}
+
+// Same result as envelope, but only keeps one block of samples in memory
+pub fn envelope_stream(stream: &mut WavStream, frame_len: usize, hop: usize) -> Envelope {
+    let mut meter = LoudnessMeter::new(stream.header.sampling_rate);
+    let mut levels: Vec<Level> = Vec::new();
+    let mut buffer: Vec<i16> = Vec::new();
+
+    loop {
+        let start = buffer.len();
+        let read = stream.read(&mut buffer, BLOCK_FRAMES * hop);
+        meter.push(&buffer[start..]);
+
+        let mut pos = 0;
+        while pos + frame_len <= buffer.len() {
+            levels.push(Level::of(&buffer[pos..pos + frame_len]));
+            pos += hop;
+        }
+        buffer.drain(..pos.min(buffer.len()));
+        if read == 0 {
+            break;
+        }
+    }
+
+    Envelope { levels, loudness: meter.integrated() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_wav;
+
+    fn sine(frequency: f64, amplitude: impl Fn(f64) -> f64, sample_rate: u32, seconds: f64) -> Vec<i16> {
+        let len = (seconds * sample_rate as f64) as usize;
+        (0..len)
+            .map(|n| {
+                let t = n as f64 / sample_rate as f64;
+                (amplitude(t) * 32767.0 * (2.0 * PI * frequency * t).sin()).round() as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_scale_sine_loudness() {
+        let data = sine(1000.0, |_| 1.0, 48000, 3.0);
+        let loudness = envelope(&data, 48000, 2400, 480).loudness.unwrap();
+        assert!((loudness + 3.01).abs() < 0.05, "{} LUFS", loudness);
+    }
+
+    #[test]
+    fn streamed_envelope_matches() {
+        let sample_rate = 8000;
+        let (frame_len, hop) = (400, 80);
+        // A swell so that every frame has a different level
+        let data = sine(300.0, |t| 0.1 + 0.15 * t, sample_rate, 6.0);
+        assert!(data.len() > 2 * BLOCK_FRAMES * hop);
+        let file = std::env::temp_dir().join(format!("wave-rs-envelope-stream-{}.wav", std::process::id()));
+        let file = file.to_str().unwrap();
+        write_wav(file, sample_rate, data.clone());
+
+        let whole = envelope(&data, sample_rate, frame_len, hop);
+        let streamed = envelope_stream(&mut WavStream::open(file).unwrap(), frame_len, hop);
+        std::fs::remove_file(file).unwrap();
+
+        assert_eq!(whole.levels, streamed.levels);
+        assert_eq!(whole.loudness, streamed.loudness);
+    }
+}
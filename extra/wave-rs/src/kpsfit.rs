@@ -0,0 +1,225 @@
+use std::{f64::consts::PI, fs::File, io::Write, process};
+
+use crate::{contour::{ContourOptions, Smoothing}, option_value, read_audio, spectral::Window, spectrogram::{stft, Spectrogram}};
+
+// Harmonics considered for the brightness fit
+const HARMONICS: usize = 8;
+// Only the part of the decay within this range below its peak is fitted
+const FIT_RANGE_DB: f64 = 40.0;
+// A harmonic needs this many frames in the fit range to get a T60
+const MIN_FIT_FRAMES: usize = 4;
+
+// Parameters of the Karplus-Strong string in kps-rs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KpsParams {
+    pub frequency: f64,
+    pub t60: f64,
+    // Loop gain for the two tap averaging loss filter of kps
+    pub feedback: f64,
+    // 1.0 - pole of a one pole low pass matching the decay of the upper harmonics, 1.0 is no loss
+    pub brightness: f64
+}
+
+// Level in dB of the strongest bin near the frequency, per frame
+fn partial_track(spec: &Spectrogram, frequency: f64, width: f64) -> Vec<f64> {
+    let bin = spec.bin_frequency(1);
+    let lo = ((frequency - width) / bin).floor().max(0.0) as usize;
+    let hi = (((frequency + width) / bin).ceil() as usize).min(spec.size / 2);
+    spec.frames
+        .iter()
+        .map(|frame| frame[lo..=hi].iter().cloned().fold(f64::MIN, f64::max))
+        .collect()
+}
+
+// T60 in seconds from a least squares line through the decay after the peak
+fn decay_time(track: &[f64], frame_time: f64) -> Option<f64> {
+    let (peak_frame, peak) = track
+        .iter()
+        .enumerate()
+        .fold((0, f64::MIN), |best, (i, db)| if *db > best.1 { (i, *db) } else { best });
+
+    let points: Vec<(f64, f64)> = track[peak_frame..]
+        .iter()
+        .enumerate()
+        .take_while(|(_, db)| **db > peak - FIT_RANGE_DB)
+        .map(|(i, db)| (i as f64 * frame_time, *db))
+        .collect();
+    if points.len() < MIN_FIT_FRAMES {
+        return None;
+    }
+
+    let n = points.len() as f64;
+    let mean_t = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_db = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let cov: f64 = points.iter().map(|p| (p.0 - mean_t) * (p.1 - mean_db)).sum();
+    let var: f64 = points.iter().map(|p| (p.0 - mean_t) * (p.0 - mean_t)).sum();
+    let slope = cov / var;
+    if slope >= 0.0 {
+        return None;
+    }
+    Some(-60.0 / slope)
+}
+
+// Loop gain per period that decays by 60 dB in t60 seconds
+fn period_gain(t60: f64, frequency: f64) -> f64 {
+    10f64.powf(-3.0 / (t60 * frequency))
+}
+
+fn one_pole_gain(pole: f64, w: f64) -> f64 {
+    (1.0 - pole) / (1.0 - 2.0 * pole * w.cos() + pole * pole).sqrt()
+}
+
+// Pole whose relative attenuation of the harmonics best matches the measured per period gains
+fn fit_pole(gains: &[(usize, f64)], w0: f64) -> f64 {
+    let g1 = match gains.iter().find(|(k, _)| *k == 1) {
+        Some((_, g)) => *g,
+        None => return 0.0
+    };
+
+    let mut best = 0.0;
+    let mut best_err = f64::MAX;
+    for step in 0..1000 {
+        let pole = step as f64 / 1000.0;
+        let err: f64 = gains
+            .iter()
+            .map(|(k, g)| {
+                let model = (one_pole_gain(pole, *k as f64 * w0) / one_pole_gain(pole, w0)).ln();
+                let measured = (g / g1).ln();
+                (model - measured) * (model - measured)
+            })
+            .sum();
+        if err < best_err {
+            best = pole;
+            best_err = err;
+        }
+    }
+    best
+}
+
+pub fn estimate(samples: &[i16], sample_rate: u32, options: &ContourOptions) -> Option<KpsParams> {
+    let contour = options.contour(samples, sample_rate);
+    let mut voiced: Vec<f64> = contour.frequencies.iter().copied().filter(|f| *f > 0.0).collect();
+    if voiced.is_empty() {
+        return None;
+    }
+    voiced.sort_by(|a, b| a.total_cmp(b));
+    let mut frequency = voiced[voiced.len() / 2];
+
+    let size = 4096;
+    let spec = stft(samples, sample_rate, Window::Hann, size, size / 8);
+    let frame_time = spec.hop as f64 / sample_rate as f64;
+
+    // When the period is not a whole number of samples the contour can settle on twice the period,
+    // a fundamental with no energy below its octave is such a subharmonic
+    let level = |f: f64| partial_track(&spec, f, f / 4.0).into_iter().fold(f64::MIN, f64::max);
+    while 2.0 * frequency < sample_rate as f64 / 2.0 && level(2.0 * frequency) - level(frequency) > FIT_RANGE_DB {
+        frequency *= 2.0;
+    }
+
+    let mut gains: Vec<(usize, f64)> = Vec::new();
+    let mut t60 = None;
+    for k in 1..=HARMONICS {
+        let partial = k as f64 * frequency;
+        if partial > sample_rate as f64 / 2.0 {
+            break;
+        }
+        if let Some(t) = decay_time(&partial_track(&spec, partial, frequency / 4.0), frame_time) {
+            if k == 1 {
+                t60 = Some(t);
+            }
+            gains.push((k, period_gain(t, frequency)));
+        }
+    }
+    let t60 = t60?;
+
+    // The averaging filter of kps attenuates the fundamental by cos(pi f / sr)
+    let w0 = 2.0 * PI * frequency / sample_rate as f64;
+    let feedback = (period_gain(t60, frequency) / (w0 / 2.0).cos()).min(1.0);
+    let brightness = 1.0 - fit_pole(&gains, w0);
+
+    Some(KpsParams { frequency, t60, feedback, brightness })
+}
+
+pub fn run(args: &[String]) {
+    if args.len() < 2 {
+        eprintln!("Usage: wave-rs kps-fit <in.wav> <out.kps> [--method <method>] [--smooth <smoothing>]");
+        process::exit(1);
+    }
+
+    let (sample_rate, samples) = read_audio(&args[0], args);
+    let mut options = ContourOptions::from_args(args, sample_rate);
+    // Plucks start with a noise burst, the tracker needs candidates to get through it
+    if option_value(args, "--smooth").is_none() {
+        options.smoothing = Smoothing::Viterbi(5);
+    }
+
+    let params = match estimate(&samples, sample_rate, &options) {
+        Some(params) => params,
+        None => panic!("Could not measure a decaying pitch!")
+    };
+
+    println!("Frequency: {:.2} Hz", params.frequency);
+    println!("T60: {:.3} s", params.t60);
+    println!("Feedback: {:.6}", params.feedback);
+    println!("Brightness: {:.3}", params.brightness);
+
+    let mut file = File::create(&args[1]).unwrap();
+    writeln!(file, "# kps preset estimated from {}", args[0]).unwrap();
+    writeln!(file, "frequency = {:.4}", params.frequency).unwrap();
+    writeln!(file, "feedback = {:.6}", params.feedback).unwrap();
+    writeln!(file, "brightness = {:.4}", params.brightness).unwrap();
+    writeln!(file, "t60 = {:.4}", params.t60).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{synthesize, SynthParams, Waveform, PLUCK_FEEDBACK};
+
+    #[test]
+    fn pluck_parameters_are_recovered() {
+        let sample_rate = 44100;
+        for frequency in [220.0, 441.0] {
+            let params = SynthParams {
+                waveform: Waveform::Pluck,
+                frequency,
+                duration: 2.0,
+                sample_rate,
+                amplitude: 0.5,
+                noise: 0.0,
+                vibrato_rate: 5.0,
+                vibrato_depth: 0.0,
+                seed: 1
+            };
+            let data: Vec<i16> = synthesize(&params).iter().map(|s| (s * 32767.0).round() as i16).collect();
+            let mut options = ContourOptions::from_args(&[], sample_rate);
+            options.smoothing = Smoothing::Viterbi(5);
+            let fit = estimate(&data, sample_rate, &options).unwrap();
+
+            // The pluck loses the feedback and its three tap loop filter once per period,
+            // the taps depend on the fraction of the delay
+            let w0 = 2.0 * PI * frequency / sample_rate as f64;
+            let delay = sample_rate as f64 / frequency - 0.5;
+            let fr = (1.0 - delay.fract()).fract();
+            let filter = 0.5 * ((1.0 + w0.cos()).powi(2) + ((1.0 - 2.0 * fr) * w0.sin()).powi(2)).sqrt();
+            let t60 = -3.0 / (frequency * (PLUCK_FEEDBACK * filter).log10());
+            // Feedback of the kps string with the same decay, its averaging filter has a gain of cos(w0 / 2)
+            let feedback = PLUCK_FEEDBACK * filter / (w0 / 2.0).cos();
+
+            assert!((fit.frequency - frequency).abs() < 0.01 * frequency, "found {} Hz for {} Hz", fit.frequency, frequency);
+            assert!((fit.feedback - feedback).abs() < 0.0002, "feedback {} instead of {} at {} Hz", fit.feedback, feedback, frequency);
+            assert!((fit.feedback - PLUCK_FEEDBACK).abs() < 0.001, "feedback {} at {} Hz", fit.feedback, frequency);
+            assert!((fit.t60 / t60 - 1.0).abs() < 0.05, "T60 {} s instead of {} s at {} Hz", fit.t60, t60, frequency);
+        }
+    }
+
+    #[test]
+    fn fit_pole_recovers_the_pole() {
+        let w0 = 2.0 * PI * 220.0 / 44100.0;
+        let pole = 0.35;
+        let gains: Vec<(usize, f64)> = (1..=HARMONICS)
+            .map(|k| (k, 0.99 * one_pole_gain(pole, k as f64 * w0) / one_pole_gain(pole, w0)))
+            .collect();
+        assert!((fit_pole(&gains, w0) - pole).abs() < 0.002);
+    }
+}
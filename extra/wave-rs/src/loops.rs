@@ -0,0 +1,206 @@
+use std::{fs::{self, File}, io::Write, process};
+
+use crate::{contour::ContourOptions, envelope::envelope, option_value, read_audio};
+
+// Frames within this range of the loudest frame belong to the sustain
+const SUSTAIN_RANGE_DB: f64 = 6.0;
+// A loop end may move this fraction of a period to reach a zero crossing
+const END_TOLERANCE: f64 = 0.25;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopPoints {
+    pub start: usize,
+    // First sample after the loop, playback jumps from end - 1 back to start
+    pub end: usize,
+    // Squared difference of the periods around the splice relative to their energy
+    pub cost: f64
+}
+
+fn upward_zero_crossings(data: &[i16], from: usize, to: usize) -> Vec<usize> {
+    (from.max(1)..to.min(data.len()))
+        .filter(|i| data[i - 1] < 0 && data[*i] >= 0)
+        .collect()
+}
+
+// ASDF of the period before and after both points, 0.0 means the loop is seamless
+fn splice_cost(data: &[i16], start: usize, end: usize, period: usize) -> f64 {
+    let mut diff = 0.0;
+    let mut energy = 0.0;
+    for i in 0..2 * period {
+        let a = data[start - period + i] as f64;
+        let b = data[end - period + i] as f64;
+        diff += (a - b) * (a - b);
+        energy += a * a + b * b;
+    }
+    if energy <= 0.0 {
+        return 0.0;
+    }
+    diff / energy
+}
+
+// Best pair of upward zero crossings a whole number of periods apart within the region
+pub fn find_loop(data: &[i16], region: (usize, usize), period: f64, min_len: usize) -> Option<LoopPoints> {
+    let lag = period.round() as usize;
+    let lo = region.0.max(lag);
+    let hi = region.1.min(data.len().saturating_sub(lag));
+    let crossings = upward_zero_crossings(data, lo, hi);
+    let tolerance = END_TOLERANCE * period;
+
+    let mut best: Option<LoopPoints> = None;
+    for &start in &crossings {
+        let mut k = (min_len as f64 / period).ceil().max(1.0);
+        while start as f64 + k * period - tolerance < hi as f64 {
+            let target = start as f64 + k * period;
+            k += 1.0;
+
+            let i = crossings.partition_point(|c| (*c as f64) < target - tolerance);
+            let end = match crossings[i..]
+                .iter()
+                .take_while(|c| (**c as f64) <= target + tolerance)
+                .min_by(|a, b| (**a as f64 - target).abs().total_cmp(&(**b as f64 - target).abs()))
+            {
+                Some(end) => *end,
+                None => continue
+            };
+
+            let cost = splice_cost(data, start, end, lag);
+            if best.is_none_or(|b| cost < b.cost) {
+                best = Some(LoopPoints { start, end, cost });
+            }
+        }
+    }
+    best
+}
+
+// Longest run of frames close to the loudest one, in samples
+fn sustain_region(data: &[i16], sample_rate: u32, frame_len: usize, hop: usize) -> (usize, usize) {
+    let levels = envelope(data, sample_rate, frame_len, hop).levels;
+    let loudest = levels.iter().map(|l| l.rms_db()).fold(f64::MIN, f64::max);
+
+    let mut best = (0, 0);
+    let mut run_start = 0;
+    for (i, level) in levels.iter().enumerate() {
+        if level.rms_db() < loudest - SUSTAIN_RANGE_DB {
+            run_start = i + 1;
+        } else if i + 1 - run_start > best.1 - best.0 {
+            best = (run_start, i + 1);
+        }
+    }
+    (best.0 * hop, ((best.1.max(1) - 1) * hop + frame_len).min(data.len()))
+}
+
+// Sampler chunk with one forward loop, as read by samplers and most editors
+fn smpl_chunk(points: &LoopPoints, sample_rate: u32, frequency: f64) -> Vec<u8> {
+    let note = 69.0 + 12.0 * (frequency / 440.0).log2();
+    let unity = note.floor();
+    let fraction = ((note - unity) * 4294967296.0) as u32;
+
+    let fields: [u32; 15] = [
+        0, 0,
+        (1e9 / sample_rate as f64).round() as u32,
+        unity.clamp(0.0, 127.0) as u32,
+        fraction,
+        0, 0,
+        1, 0,
+        // The loop itself, end is the last sample played
+        0, 0,
+        points.start as u32,
+        (points.end - 1) as u32,
+        0, 0
+    ];
+
+    let mut chunk: Vec<u8> = Vec::new();
+    chunk.extend_from_slice(b"smpl");
+    chunk.extend_from_slice(&(fields.len() as u32 * 4).to_le_bytes());
+    for field in fields {
+        chunk.extend_from_slice(&field.to_le_bytes());
+    }
+    chunk
+}
+
+// Copies the wav file chunk by chunk, replacing any previous smpl chunk
+fn write_smpl(in_file: &str, out_file: &str, chunk: &[u8]) {
+    let data = fs::read(in_file).unwrap();
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        panic!("Loop points can only be written into wav files!");
+    }
+
+    let mut out: Vec<u8> = data[0..12].to_vec();
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let size = u32::from_le_bytes([data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]]) as usize;
+        let next = (pos + 8 + size + size % 2).min(data.len());
+        if &data[pos..pos + 4] != b"smpl" {
+            out.extend_from_slice(&data[pos..next]);
+        }
+        pos = next;
+    }
+    out.extend_from_slice(chunk);
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    fs::write(out_file, out).unwrap();
+}
+
+pub fn run(args: &[String]) {
+    if args.len() < 2 {
+        eprintln!("Usage: wave-rs loop <in.wav> <out.wav|out.loop> [--from <s>] [--to <s>] [--min-length <s>] [--method <method>] [--smooth <smoothing>]");
+        process::exit(1);
+    }
+
+    let (sample_rate, samples) = read_audio(&args[0], args);
+    let options = ContourOptions::from_args(args, sample_rate);
+    let seconds = |name: &str| option_value(args, name).map(|s| (s.parse::<f64>().expect("Invalid time") * sample_rate as f64) as usize);
+
+    let sustain = sustain_region(&samples, sample_rate, options.frame_len, options.hop);
+    let region = (seconds("--from").unwrap_or(sustain.0), seconds("--to").unwrap_or(sustain.1).min(samples.len()));
+    let min_len = seconds("--min-length").unwrap_or((region.1.saturating_sub(region.0)) / 2);
+
+    let contour = options.contour(&samples[region.0..region.1], sample_rate);
+    let mut voiced: Vec<f64> = contour.frequencies.iter().copied().filter(|f| *f > 0.0).collect();
+    if voiced.is_empty() {
+        panic!("Could not find a period in the sustain!");
+    }
+    voiced.sort_by(|a, b| a.total_cmp(b));
+    let frequency = voiced[voiced.len() / 2];
+
+    let points = match find_loop(&samples, region, sample_rate as f64 / frequency, min_len) {
+        Some(points) => points,
+        None => panic!("Could not find a loop in the sustain!")
+    };
+
+    println!("Sustain: {:.3} s to {:.3} s", region.0 as f64 / sample_rate as f64, region.1 as f64 / sample_rate as f64);
+    println!("Loop: {} to {} ({} samples)", points.start, points.end, points.end - points.start);
+    println!("Splice Difference: {:.6}", points.cost);
+
+    if args[1].ends_with(".wav") {
+        write_smpl(&args[0], &args[1], &smpl_chunk(&points, sample_rate, frequency));
+    } else {
+        let mut file = File::create(&args[1]).unwrap();
+        writeln!(file, "# loop points of {}, end is the first sample after the loop", args[0]).unwrap();
+        writeln!(file, "start = {}", points.start).unwrap();
+        writeln!(file, "end = {}", points.end).unwrap();
+        writeln!(file, "frequency = {:.4}", frequency).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loop_spans_whole_periods() {
+        let period = 73.5;
+        let data: Vec<i16> = (0..20000)
+            .map(|i| {
+                let phase = std::f64::consts::TAU * i as f64 / period;
+                (8000.0 * (phase.sin() + 0.5 * (2.0 * phase).sin())) as i16
+            })
+            .collect();
+        let points = find_loop(&data, (1000, 19000), period, 5000).unwrap();
+
+        let periods = (points.end - points.start) as f64 / period;
+        assert!((periods - periods.round()).abs() < 0.05, "{} periods", periods);
+        assert!(points.cost < 1e-3);
+    }
+}
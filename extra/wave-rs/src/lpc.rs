@@ -0,0 +1,187 @@
+use std::f64::consts::PI;
+
+use rustfft::num_complex::Complex;
+
+use crate::{contour::BLOCK_FRAMES, stream::WavStream};
+
+// First difference filter that flattens the spectral tilt of the glottal source
+const PRE_EMPHASIS: f64 = 0.97;
+// Resonances outside this range or wider than MAX_BANDWIDTH are not formants
+const MIN_FORMANT: f64 = 90.0;
+const MAX_BANDWIDTH: f64 = 400.0;
+const ROOT_ITERATIONS: usize = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Formant {
+    pub frequency: f64,
+    pub bandwidth: f64
+}
+
+// Rule of thumb, one pole pair per kHz plus two for the source and radiation
+pub fn default_order(sample_rate: u32) -> usize {
+    2 + sample_rate as usize / 1000
+}
+
+fn autocorrelation(data: &[f64], order: usize) -> Vec<f64> {
+    (0..=order)
+        .map(|lag| data.iter().zip(data.iter().skip(lag)).map(|(a, b)| a * b).sum())
+        .collect()
+}
+
+// Coefficients of A(z) = 1 + a1 z^-1 + ... + ap z^-p by Levinson-Durbin, None for silent frames
+pub fn lpc(data: &[f64], order: usize) -> Option<Vec<f64>> {
+    let r = autocorrelation(data, order);
+    if r[0] <= 0.0 {
+        return None;
+    }
+
+    let mut a = vec![0.0; order + 1];
+    a[0] = 1.0;
+    let mut err = r[0];
+    for i in 1..=order {
+        let acc: f64 = r[i] + (1..i).map(|j| a[j] * r[i - j]).sum::<f64>();
+        let k = -acc / err;
+        let prev = a.clone();
+        for j in 1..i {
+            a[j] = prev[j] + k * prev[i - j];
+        }
+        a[i] = k;
+        err *= 1.0 - k * k;
+        if err <= 0.0 {
+            break;
+        }
+    }
+    Some(a)
+}
+
+// All roots of the monic polynomial with the given coefficients, highest power first (Durand-Kerner)
+fn roots(coeffs: &[f64]) -> Vec<Complex<f64>> {
+    let degree = coeffs.len() - 1;
+    let eval = |z: Complex<f64>| coeffs.iter().fold(Complex::new(0.0, 0.0), |acc, c| acc * z + c);
+
+    let seed = Complex::new(0.4, 0.9);
+    let mut z: Vec<Complex<f64>> = (0..degree).map(|i| seed.powu(i as u32)).collect();
+    for _ in 0..ROOT_ITERATIONS {
+        let mut change = 0.0;
+        for i in 0..degree {
+            let mut denom = Complex::new(1.0, 0.0);
+            for j in 0..degree {
+                if i != j {
+                    denom *= z[i] - z[j];
+                }
+            }
+            let step = eval(z[i]) / denom;
+            z[i] -= step;
+            change = f64::max(change, step.norm());
+        }
+        if change < 1e-12 {
+            break;
+        }
+    }
+    z
+}
+
+// Resonances of the LPC model of one frame, sorted by frequency
+pub fn formants(frame: &[i16], sample_rate: u32, order: usize) -> Vec<Formant> {
+    let len = frame.len();
+    let data: Vec<f64> = (0..len)
+        .map(|i| {
+            let prev = if i > 0 { frame[i - 1] as f64 } else { 0.0 };
+            let window = 0.54 - 0.46 * (2.0 * PI * i as f64 / len as f64).cos();
+            (frame[i] as f64 - PRE_EMPHASIS * prev) * window
+        })
+        .collect();
+    let a = match lpc(&data, order) {
+        Some(a) => a,
+        None => return Vec::new()
+    };
+
+    let sr = sample_rate as f64;
+    let mut result: Vec<Formant> = roots(&a)
+        .into_iter()
+        .filter(|z| z.im > 0.0)
+        .map(|z| Formant {
+            frequency: z.arg() * sr / (2.0 * PI),
+            bandwidth: -z.norm().ln() * sr / PI
+        })
+        .filter(|f| f.frequency > MIN_FORMANT && f.frequency < sr / 2.0 - MIN_FORMANT && f.bandwidth < MAX_BANDWIDTH)
+        .collect();
+    result.sort_by(|a, b| a.frequency.total_cmp(&b.frequency));
+    result
+}
+
+// Formants with the framing of the pitch contour
+pub fn formant_track(data: &[i16], sample_rate: u32, frame_len: usize, hop: usize, order: usize) -> Vec<Vec<Formant>> {
+    let mut frames: Vec<Vec<Formant>> = Vec::new();
+    let mut pos = 0;
+    while pos + frame_len <= data.len() {
+        frames.push(formants(&data[pos..pos + frame_len], sample_rate, order));
+        pos += hop;
+    }
+    frames
+}
+
+// Same result as formant_track, but only keeps one block of samples in memory
+pub fn formant_track_stream(stream: &mut WavStream, frame_len: usize, hop: usize, order: usize) -> Vec<Vec<Formant>> {
+    let sample_rate = stream.header.sampling_rate;
+    let mut frames: Vec<Vec<Formant>> = Vec::new();
+    let mut buffer: Vec<i16> = Vec::new();
+
+    loop {
+        let read = stream.read(&mut buffer, BLOCK_FRAMES * hop);
+        let mut pos = 0;
+        while pos + frame_len <= buffer.len() {
+            frames.push(formants(&buffer[pos..pos + frame_len], sample_rate, order));
+            pos += hop;
+        }
+        buffer.drain(..pos.min(buffer.len()));
+        if read == 0 {
+            break;
+        }
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_resonances_of_an_all_pole_filter() {
+        let sample_rate = 10000;
+        // Two resonances, at 700 Hz with 80 Hz bandwidth and at 2200 Hz with 120 Hz bandwidth
+        let poles: Vec<Complex<f64>> = [(700.0, 80.0), (2200.0, 120.0)]
+            .iter()
+            .map(|(f, bw): &(f64, f64)| Complex::from_polar((-PI * bw / sample_rate as f64).exp(), 2.0 * PI * f / sample_rate as f64))
+            .collect();
+        let mut coeffs = vec![Complex::new(1.0, 0.0)];
+        for p in poles.iter().flat_map(|p| [*p, p.conj()]) {
+            let mut next = coeffs.clone();
+            next.push(Complex::new(0.0, 0.0));
+            for (i, c) in coeffs.iter().enumerate() {
+                next[i + 1] -= p * c;
+            }
+            coeffs = next;
+        }
+
+        let mut seed: u32 = 7;
+        let mut out = vec![0.0; 4000];
+        for n in 0..out.len() {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let mut y = seed as f64 / u32::MAX as f64 - 0.5;
+            for k in 1..coeffs.len() {
+                if n >= k {
+                    y -= coeffs[k].re * out[n - k];
+                }
+            }
+            out[n] = y;
+        }
+        let frame: Vec<i16> = out[1000..3000].iter().map(|y| (y * 1000.0) as i16).collect();
+
+        let found = formants(&frame, sample_rate, 8);
+        assert!(found.iter().any(|f| (f.frequency - 700.0).abs() < 50.0), "{:?}", found);
+        assert!(found.iter().any(|f| (f.frequency - 2200.0).abs() < 100.0), "{:?}", found);
+    }
+}
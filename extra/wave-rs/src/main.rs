@@ -1,8 +1,22 @@
-use std::{fs::{File, OpenOptions, self}, env, io::{ErrorKind, Write}, path::Path};
+use std::{fs::File, env, io::Write, process};
 
-use wav::header;
+mod contour;
+mod decode;
+mod envelope;
+mod kpsfit;
+mod loops;
+mod lpc;
+mod psola;
+mod smooth;
+mod spectral;
+mod spectrogram;
+mod stream;
+mod stretch;
+mod synth;
+mod wavetable;
 
-fn ASDF(data: &Vec<i16>, tau: usize) -> f64 {
+#[allow(non_snake_case)]
+fn ASDF(data: &[i16], tau: usize) -> f64 {
     let mut sum: f64 = 0.0;
     //let range = data.len() - tau;
     for i in 0..(data.len() - tau -1) {
@@ -15,7 +29,8 @@ fn ASDF(data: &Vec<i16>, tau: usize) -> f64 {
     sum/(data.len() as f64 - tau as f64)
 }
 
-fn AMDF(data: &Vec<i16>, tau: usize) -> f64 {
+#[allow(non_snake_case)]
+fn AMDF(data: &[i16], tau: usize) -> f64 {
     let mut sum: f64 = 0.0;
     //let range = data.len() - tau;
     for i in 0..(data.len() - tau -1) {
@@ -27,103 +42,290 @@ fn AMDF(data: &Vec<i16>, tau: usize) -> f64 {
     sum/(data.len() as f64 - tau as f64)
 }
 
-fn local_minima(vals: &Vec<f64>) -> Vec<usize> {
-    let mut minima: Vec<usize> = Vec::new();
+// Indices that are strictly lower than their neighbours, a flat bottom counts once at its middle
+fn local_minima(vals: &[f64]) -> Vec<usize> {
+    plateau_extrema(vals, |a, b| a < b)
+}
 
-    for i in 1..vals.len()-1 {
-        if vals[i] < vals[i+1] && vals[i] < vals[i-1] {
-            minima.push(i);
-        }
-    }
-    minima
+fn local_maxima(vals: &[f64]) -> Vec<usize> {
+    plateau_extrema(vals, |a, b| a > b)
 }
 
-fn local_maxima(vals: &Vec<f64>) -> Vec<usize> {
-    let mut maxima: Vec<usize> = Vec::new();
+fn plateau_extrema(vals: &[f64], beyond: fn(f64, f64) -> bool) -> Vec<usize> {
+    let mut extrema: Vec<usize> = Vec::new();
 
-    for i in 1..vals.len()-1 {
-        if vals[i] > vals[i+1] && vals[i] > vals[i-1] {
-            maxima.push(i);
+    let mut i = 1;
+    while i + 1 < vals.len() {
+        if !beyond(vals[i], vals[i-1]) {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end + 1 < vals.len() && vals[end+1] == vals[i] {
+            end += 1;
         }
+        if end + 1 < vals.len() && beyond(vals[i], vals[end+1]) {
+            extrema.push((i + end) / 2);
+        }
+        i = end + 1;
     }
-    maxima
+    extrema
 }
 
-fn detect_frequency_asdf(data: &Vec<i16>, sample_rate: u32) -> f64 {
+fn asdf_curve(data: &[i16]) -> Vec<f64> {
     let mut asdf_vals: Vec<f64> = Vec::new();
     for i in 0..data.len() {
         asdf_vals.push(ASDF(data, i));
     }
-
-    write_data_to_csv_f64(&asdf_vals, &String::from("asdf.csv"));
-    let samples = local_maxima(&asdf_vals);
-    let period = samples[1] - samples[0];
-    (sample_rate as f64)/(period as f64)
+    asdf_vals
 }
-fn detect_frequency_amdf(data: &Vec<i16>, sample_rate: u32) -> f64 {
+
+fn amdf_curve(data: &[i16]) -> Vec<f64> {
     let mut amdf_vals: Vec<f64> = Vec::new();
     for i in 0..data.len() {
         amdf_vals.push(AMDF(data, i));
     }
+    amdf_vals
+}
+
+// Frequency estimate of one frame with how clearly the frame is periodic, from 0.0 (noise) to 1.0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    pub frequency: f64,
+    pub confidence: f64
+}
 
-    write_data_to_csv_f64(&amdf_vals, &String::from("amdf.csv"));
-    let samples = local_minima(&amdf_vals);
+impl Estimate {
+    pub const NONE: Estimate = Estimate { frequency: 0.0, confidence: 0.0 };
+}
+
+// Depth of the difference function at the lag relative to its mean, 1.0 for a perfectly repeating frame
+pub fn dip_depth(curve: &[f64], lag: usize) -> f64 {
+    let mean = curve.iter().sum::<f64>() / curve.len().max(1) as f64;
+    if mean <= 0.0 || lag >= curve.len() {
+        return 0.0;
+    }
+    (1.0 - curve[lag] / mean).clamp(0.0, 1.0)
+}
+
+// Period is the distance between the first two extrema, 0.0 means no period was found
+fn estimate_from_extrema(samples: &[usize], curve: &[f64], sample_rate: u32) -> Estimate {
+    if samples.len() < 2 {
+        return Estimate::NONE;
+    }
     let period = samples[1] - samples[0];
-    (sample_rate as f64)/(period as f64)
+    Estimate {
+        frequency: (sample_rate as f64)/(period as f64),
+        confidence: dip_depth(curve, period)
+    }
+}
+
+fn detect_frequency_asdf(data: &[i16], sample_rate: u32) -> Estimate {
+    let asdf_vals = asdf_curve(data);
+    estimate_from_extrema(&local_maxima(&asdf_vals), &asdf_vals, sample_rate)
+}
+
+fn detect_frequency_amdf(data: &[i16], sample_rate: u32) -> Estimate {
+    let amdf_vals = amdf_curve(data);
+    estimate_from_extrema(&local_minima(&amdf_vals), &amdf_vals, sample_rate)
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let file_name = String::from("./") + &args[1];
-    let wav_file_result = File::open(file_name);
+    if args.len() < 2 {
+        eprintln!("Usage: wave-rs <file.wav|aiff|flac|raw> [--raw-format u8|s16le|s16be|s24le|s24be|s32le|s32be|f32le|f32be] [--raw-rate <hz>] [--raw-channels <n>]");
+        eprintln!("       wave-rs contour <in.wav> <out.csv> [--method amdf|asdf|cepstrum|hps] [--smooth none|median|viterbi] [--threads <count>] [--min-confidence <0-1>] [--levels] [--formants <count>] [--order <lpc order>]");
+        eprintln!("       wave-rs spectrogram <in.wav> <out.csv|out.png> [--window <window>] [--size <samples>] [--hop <samples>] [--overlay]");
+        eprintln!("       wave-rs stretch <in.wav> <out.wav> [--factor <ratio>] [--method asdf|amdf]");
+        eprintln!("       wave-rs synth <out.wav> [--wave <waveform>] [--frequency <hz>] [--duration <s>] [--rate <hz>] [--bits <bits>]");
+        eprintln!("       wave-rs kps-fit <in.wav> <out.kps>");
+        eprintln!("       wave-rs loop <in.wav> <out.wav|out.loop> [--from <s>] [--to <s>] [--min-length <s>]");
+        eprintln!("       wave-rs wavetable <in.wav> <out.wt> [--cycles <count>] [--length <samples>]");
+        eprintln!("       wave-rs psola <in.wav> <out.wav> [--method amdf|asdf|cepstrum|hps] [--smooth none|median|viterbi] [--scale <scale>] [--key <note>] [--shift <semitones>]");
+        process::exit(1);
+    }
 
-    let mut wav_file = match wav_file_result {
-        Ok(file) => file,
-        Err(error) => {
-            panic!("Could not find file!");
-        }
-    };
+    match args[1].as_str() {
+        "contour" => contour::run(&args[2..]),
+        "kps-fit" => kpsfit::run(&args[2..]),
+        "loop" => loops::run(&args[2..]),
+        "psola" => psola::run(&args[2..]),
+        "spectrogram" => spectrogram::run(&args[2..]),
+        "stretch" => stretch::run(&args[2..]),
+        "synth" => synth::run(&args[2..]),
+        "wavetable" => wavetable::run(&args[2..]),
+        file_name => analyze(file_name, &args[2..]),
+    }
+}
+
+fn analyze(file_name: &str, args: &[String]) {
+    let (sample_rate, data_vec) = read_audio(file_name, args);
+    let duration: usize = (sample_rate as usize)/1000 * 50;
+
+    let sample = &data_vec[0..duration.min(data_vec.len())];
 
-    let (header, data) = wav::read(&mut wav_file).unwrap();
-    let duration: usize = (header.sampling_rate as usize)/1000 * 50;
+    write_data_to_csv_i16(sample, "samples.csv");
 
-    let data_vec = data.as_sixteen().unwrap();
-    let sample = &data_vec[0..duration].to_vec();
+    let amdf_vals = amdf_curve(sample);
+    write_data_to_csv_f64(&amdf_vals, "amdf.csv");
+    let amdf = estimate_from_extrema(&local_minima(&amdf_vals), &amdf_vals, sample_rate);
 
-    write_data_to_csv_i16(sample, &String::from("samples.csv"));
+    println!("AMDF Frequency: {:?}", amdf.frequency);
+    println!("AMDF Confidence: {:.3}", amdf.confidence);
 
-    let sample_rate = header.sampling_rate;
-    let amdf_frequency = detect_frequency_amdf(sample, sample_rate);
+    let asdf_vals = asdf_curve(sample);
+    write_data_to_csv_f64(&asdf_vals, "asdf.csv");
+    let asdf = estimate_from_extrema(&local_maxima(&asdf_vals), &asdf_vals, sample_rate);
+    println!("ASDF Frequency: {:?}", asdf.frequency);
+    println!("ASDF Confidence: {:.3}", asdf.confidence);
 
-    println!("AMDF Frequency: {:?}", amdf_frequency);
-    
-    let asdf_frequency = detect_frequency_asdf(sample, sample_rate);
-    println!("ASDF Frequency: {:?}", asdf_frequency);
+    let cepstrum = spectral::detect_frequency_cepstrum(sample, sample_rate);
+    println!("Cepstrum Frequency: {:?}", cepstrum.frequency);
+    println!("Cepstrum Confidence: {:.3}", cepstrum.confidence);
+    let hps = spectral::detect_frequency_hps(sample, sample_rate);
+    println!("HPS Frequency: {:?}", hps.frequency);
+    println!("HPS Confidence: {:.3}", hps.confidence);
 
+    let order = option_value(args, "--order").map(|o| o.parse().expect("Invalid order")).unwrap_or(lpc::default_order(sample_rate));
+    let formants: Vec<String> = lpc::formants(sample, sample_rate, order)
+        .iter()
+        .map(|f| format!("{:.0} Hz ({:.0} Hz)", f.frequency, f.bandwidth))
+        .collect();
+    println!("Formants: {}", formants.join(", "));
+
+}
+
+// Sample rate and first channel of a wav, aiff, flac or raw file, normalized to 16 bit
+fn read_audio(file_name: &str, args: &[String]) -> (u32, Vec<i16>) {
+    let decoded = decode::decode(file_name, decode::RawFormat::from_args(args));
+    (decoded.sample_rate, decoded.first_channel_i16())
+}
+
+fn write_wav(file_name: &str, sample_rate: u32, data: Vec<i16>) {
+    let header = wav::Header::new(wav::header::WAV_FORMAT_PCM, 1, sample_rate, 16);
+    let mut file = File::create(file_name).unwrap();
+    wav::write(header, &wav::BitDepth::Sixteen(data), &mut file).unwrap();
+}
+
+// Writes samples in [-1, 1] with the given bit depth, 32 bits are written as float
+fn write_wav_f64(file_name: &str, sample_rate: u32, bits: u16, data: &[f64]) {
+    let clamped = data.iter().map(|s| s.clamp(-1.0, 1.0));
+    let (format, track) = match bits {
+        8 => (wav::header::WAV_FORMAT_PCM, wav::BitDepth::Eight(
+            clamped.map(|s| (s * 127.0 + 128.0).round() as u8).collect()
+        )),
+        16 => (wav::header::WAV_FORMAT_PCM, wav::BitDepth::Sixteen(
+            clamped.map(|s| (s * i16::MAX as f64).round() as i16).collect()
+        )),
+        // The wav crate keeps 24 bit samples in the upper bytes of an i32
+        24 => (wav::header::WAV_FORMAT_PCM, wav::BitDepth::TwentyFour(
+            clamped.map(|s| ((s * 8388607.0).round() as i32) << 8).collect()
+        )),
+        32 => (wav::header::WAV_FORMAT_IEEE_FLOAT, wav::BitDepth::ThirtyTwoFloat(
+            clamped.map(|s| s as f32).collect()
+        )),
+        _ => panic!("Unsupported bit depth!")
+    };
+    let header = wav::Header::new(format, 1, sample_rate, bits);
+    let mut file = File::create(file_name).unwrap();
+    wav::write(header, &track, &mut file).unwrap();
 }
 
-fn write_data_to_csv_f64(data: &Vec<f64>, file_name: &String) {
-    let path = String::from("./") + file_name;
-    if Path::new(&path).exists() {
-        fs::remove_file(&path).unwrap();
+// Value following a "--name" flag, if present
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|val| val.as_str())
+}
+
+fn write_data_to_csv_f64(data: &[f64], file_name: &str) {
+    let mut file = File::create(file_name).unwrap();
+    for dp in data {
+        writeln!(file, "{:.2}", dp).unwrap();
     }
 
-    let mut file = OpenOptions::new().create(true).write(true).append(false).open(path).unwrap();
+}
+
+fn write_data_to_csv_i16(data: &[i16], file_name: &str) {
+    let mut file = File::create(file_name).unwrap();
     for dp in data {
-        write!(file, "{:.2}\n", dp);
+        writeln!(file, "{}", dp).unwrap();
     }
-    
+
 }
 
-fn write_data_to_csv_i16(data: &Vec<i16>, file_name: &String) {
-    let path = String::from("./") + file_name;
-    if Path::new(&path).exists() {
-        fs::remove_file(&path).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extrema_of_short_inputs_are_empty() {
+        assert!(local_minima(&[]).is_empty());
+        assert!(local_maxima(&[]).is_empty());
+        assert!(local_minima(&[1.0]).is_empty());
+        assert!(local_maxima(&[1.0, 0.0]).is_empty());
     }
 
-    let mut file = OpenOptions::new().create(true).write(true).append(false).open(path).unwrap();
-    for dp in data {
-        write!(file, "{}\n", dp);
+    #[test]
+    fn extrema_of_monotonic_data_are_empty() {
+        let rising = [0.0, 1.0, 2.0, 3.0, 4.0];
+        let falling = [4.0, 3.0, 2.0, 1.0, 0.0];
+        assert!(local_minima(&rising).is_empty());
+        assert!(local_maxima(&rising).is_empty());
+        assert!(local_minima(&falling).is_empty());
+        assert!(local_maxima(&falling).is_empty());
+    }
+
+    #[test]
+    fn extrema_of_constant_data_are_empty() {
+        let flat = [2.0; 6];
+        assert!(local_minima(&flat).is_empty());
+        assert!(local_maxima(&flat).is_empty());
+    }
+
+    #[test]
+    fn plateau_counts_once_at_its_middle() {
+        let valley = [3.0, 1.0, 1.0, 1.0, 3.0];
+        assert_eq!(local_minima(&valley), vec![2]);
+        assert!(local_maxima(&valley).is_empty());
+
+        let hill = [0.0, 2.0, 2.0, 0.0, 1.0, 0.0];
+        assert_eq!(local_maxima(&hill), vec![1, 4]);
+        assert_eq!(local_minima(&hill), vec![3]);
+    }
+
+    #[test]
+    fn shoulder_is_not_an_extremum() {
+        let shoulder = [3.0, 2.0, 2.0, 1.0, 2.0];
+        assert_eq!(local_minima(&shoulder), vec![3]);
+        assert!(local_maxima(&shoulder).is_empty());
+    }
+
+    #[test]
+    fn extrema_at_the_edges_are_ignored() {
+        let vals = [0.0, 1.0, 0.5, 1.0, 0.0];
+        assert_eq!(local_minima(&vals), vec![2]);
+        assert_eq!(local_maxima(&vals), vec![1, 3]);
     }
-    
-}
\ No newline at end of file
+
+    #[test]
+    fn repeating_frame_is_confident() {
+        let period = 20;
+        let tone: Vec<i16> = (0..400).map(|i| ((i % period) as i16 - 10) * 1000).collect();
+        let estimate = detect_frequency_amdf(&tone, 2000);
+        assert_eq!(estimate.frequency, 100.0);
+        assert!(estimate.confidence > 0.9);
+
+        let mut seed: u32 = 1;
+        let noise: Vec<i16> = (0..400)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as i16
+            })
+            .collect();
+        assert!(detect_frequency_amdf(&noise, 2000).confidence < 0.5);
+    }
+}
@@ -0,0 +1,231 @@
+use std::{f64::consts::PI, process};
+
+use crate::{contour::{Contour, ContourOptions}, option_value, read_audio, write_wav};
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+const SCALES: [(&str, &[i32]); 5] = [
+    ("chromatic", &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+    ("major", &[0, 2, 4, 5, 7, 9, 11]),
+    ("minor", &[0, 2, 3, 5, 7, 8, 10]),
+    ("pentatonic", &[0, 2, 4, 7, 9]),
+    ("blues", &[0, 3, 5, 6, 7, 10])
+];
+
+// Spacing of the pitch marks in unvoiced parts, in seconds, at least one sample at very low rates
+const UNVOICED_PERIOD: f64 = 0.01;
+
+pub struct Target {
+    scale: Option<&'static [i32]>,
+    key: i32,
+    shift: f64
+}
+
+impl Target {
+    pub fn new(scale: Option<&str>, key: &str, shift: f64) -> Option<Target> {
+        let scale = match scale {
+            Some(name) => Some(SCALES.iter().find(|(n, _)| *n == name)?.1),
+            None => None
+        };
+        let key = NOTE_NAMES.iter().position(|n| *n == key)? as i32;
+        Some(Target { scale, key, shift })
+    }
+
+    pub fn frequency(&self, frequency: f64) -> f64 {
+        let mut note = 69.0 + 12.0 * (frequency / 440.0).log2();
+        if let Some(scale) = self.scale {
+            note = snap(note, scale, self.key);
+        }
+        440.0 * 2f64.powf((note + self.shift - 69.0) / 12.0)
+    }
+}
+
+// Nearest midi note that lies in the scale
+fn snap(note: f64, scale: &[i32], key: i32) -> f64 {
+    let nearest = note.round() as i32;
+    let mut best = nearest;
+    let mut best_dist = f64::MAX;
+    for n in (nearest - 12)..=(nearest + 12) {
+        let dist = (n as f64 - note).abs();
+        if scale.contains(&(n - key).rem_euclid(12)) && dist < best_dist {
+            best = n;
+            best_dist = dist;
+        }
+    }
+    best as f64
+}
+
+// Places one mark per period on the waveform peak, using the contour as period estimate
+pub fn pitch_marks(data: &[f64], contour: &Contour, sample_rate: u32) -> Vec<usize> {
+    let mut marks: Vec<usize> = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let frequency = contour.frequency_at(pos);
+        if frequency <= 0.0 {
+            marks.push(pos);
+            pos += ((UNVOICED_PERIOD * sample_rate as f64) as usize).max(1);
+            continue;
+        }
+
+        let period = sample_rate as f64 / frequency;
+        let (lo, hi) = match marks.last() {
+            Some(&last) if last < pos => (pos, pos + period as usize),
+            Some(_) => (pos + ((0.75 * period) as usize).max(1), pos + (1.25 * period) as usize + 1),
+            None => (0, period as usize)
+        };
+        let hi = hi.min(data.len());
+        if lo >= hi {
+            break;
+        }
+
+        let mut peak = lo;
+        for i in lo..hi {
+            if data[i] > data[peak] {
+                peak = i;
+            }
+        }
+        marks.push(peak);
+        pos = peak;
+    }
+    marks
+}
+
+pub fn psola(data: &[f64], marks: &[usize], contour: &Contour, target: &Target, sample_rate: u32) -> Vec<f64> {
+    let mut out = vec![0.0; data.len()];
+    let mut weights = vec![0.0; data.len()];
+    if marks.is_empty() {
+        return out;
+    }
+
+    let unvoiced = ((UNVOICED_PERIOD * sample_rate as f64) as usize).max(1);
+    let mut k = 0;
+    let mut t = marks[0] as f64;
+    while (t as usize) < data.len() {
+        let ts = t as usize;
+        while k + 1 < marks.len() && marks[k + 1].abs_diff(ts) <= marks[k].abs_diff(ts) {
+            k += 1;
+        }
+        let ta = marks[k];
+
+        let period = if k + 1 < marks.len() {
+            marks[k + 1] - ta
+        } else if k > 0 {
+            ta - marks[k - 1]
+        } else {
+            unvoiced
+        }.max(1);
+
+        let frequency = contour.frequency_at(ta);
+        let ratio = if frequency > 0.0 { target.frequency(frequency) / frequency } else { 1.0 };
+
+        // Hann windowed grain of two periods, moved from the analysis to the synthesis mark
+        for j in 0..2 * period {
+            let src = (ta + j) as isize - period as isize;
+            let dst = (ts + j) as isize - period as isize;
+            if src < 0 || dst < 0 || src as usize >= data.len() || dst as usize >= data.len() {
+                continue;
+            }
+            let w = 0.5 - 0.5 * f64::cos(PI * j as f64 / period as f64);
+            out[dst as usize] += w * data[src as usize];
+            weights[dst as usize] += w;
+        }
+
+        t += period as f64 / ratio;
+    }
+
+    for (o, w) in out.iter_mut().zip(weights.iter()) {
+        if *w > 0.1 {
+            *o /= *w;
+        }
+    }
+    out
+}
+
+pub fn run(args: &[String]) {
+    if args.len() < 2 {
+        eprintln!("Usage: wave-rs psola <in.wav> <out.wav> [--method amdf|asdf|cepstrum|hps] [--smooth none|median|viterbi] [--scale none|chromatic|major|minor|pentatonic|blues] [--key <note>] [--shift <semitones>]");
+        process::exit(1);
+    }
+
+    let scale = match option_value(args, "--scale") {
+        Some("none") => None,
+        Some(name) => Some(name),
+        None => Some("chromatic")
+    };
+    let key = option_value(args, "--key").unwrap_or("C");
+    let shift: f64 = option_value(args, "--shift").map(|s| s.parse().expect("Invalid shift")).unwrap_or(0.0);
+    let target = Target::new(scale, key, shift).expect("Unknown scale or key");
+
+    let (sample_rate, samples) = read_audio(&args[0], args);
+    let contour = ContourOptions::from_args(args, sample_rate).contour(&samples, sample_rate);
+    let data: Vec<f64> = samples.iter().map(|s| *s as f64).collect();
+    let marks = pitch_marks(&data, &contour, sample_rate);
+    let out = psola(&data, &marks, &contour, &target, sample_rate);
+
+    let out: Vec<i16> = out.iter()
+        .map(|s| s.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16)
+        .collect();
+    write_wav(&args[1], sample_rate, out);
+
+    println!("Placed {} pitch marks in {} frames", marks.len(), contour.frequencies.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snap_stays_in_the_key() {
+        let d_major = SCALES[1].1;
+        // F is between E and F# in D major, ties go to the lower note
+        assert_eq!(snap(65.0, d_major, 2), 64.0);
+        assert_eq!(snap(65.4, d_major, 2), 66.0);
+        assert_eq!(snap(61.2, d_major, 2), 61.0);
+        // C# is between C and D in A minor
+        assert_eq!(snap(61.0, SCALES[2].1, 9), 60.0);
+    }
+
+    #[test]
+    fn shift_moves_the_target() {
+        let octave = Target::new(None, "C", 12.0).unwrap();
+        assert!((octave.frequency(220.0) - 440.0).abs() < 1e-9);
+        // Snaps a sharp A to A first, then shifts it down to G
+        let down = Target::new(Some("chromatic"), "C", -2.0).unwrap();
+        assert!((down.frequency(445.0) - 391.995).abs() < 1e-3);
+    }
+
+    #[test]
+    fn shifted_sine_has_the_target_pitch() {
+        let sample_rate = 16000;
+        let data: Vec<f64> = (0..sample_rate)
+            .map(|i| 10000.0 * (2.0 * PI * 200.0 * i as f64 / sample_rate as f64).sin())
+            .collect();
+        let samples: Vec<i16> = data.iter().map(|s| *s as i16).collect();
+        let contour = ContourOptions::from_args(&[], sample_rate).contour(&samples, sample_rate);
+        let target = Target::new(None, "C", 3.0).unwrap();
+
+        let marks = pitch_marks(&data, &contour, sample_rate);
+        let out = psola(&data, &marks, &contour, &target, sample_rate);
+
+        // Upward zero crossings of the middle half, interpolated between samples
+        let crossings: Vec<f64> = (sample_rate as usize / 4..3 * sample_rate as usize / 4)
+            .filter(|i| out[i - 1] < 0.0 && out[*i] >= 0.0)
+            .map(|i| i as f64 - out[i] / (out[i] - out[i - 1]))
+            .collect();
+        let measured = (crossings.len() - 1) as f64 * sample_rate as f64 / (crossings[crossings.len() - 1] - crossings[0]);
+        let expected = target.frequency(200.0);
+        assert!((measured - expected).abs() < 0.01 * expected, "{} != {}", measured, expected);
+    }
+
+    #[test]
+    fn unvoiced_marks_advance_at_low_rates() {
+        let data = vec![0.0; 100];
+        let contour = Contour { frame_len: 2, hop: 1, frequencies: vec![0.0; 99], confidences: vec![0.0; 99] };
+        let target = Target::new(None, "C", 0.0).unwrap();
+
+        let marks = pitch_marks(&data, &contour, 50);
+        assert_eq!(marks.len(), 100);
+        assert_eq!(psola(&data, &marks, &contour, &target, 50).len(), 100);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{contour::{Method, MAX_FREQUENCY, MIN_FREQUENCY}, local_minima, Estimate};
+
+// Local cost of declaring a frame unvoiced
+const UNVOICED_COST: f64 = 0.6;
+// Transition cost between voiced and unvoiced frames
+const VOICING_COST: f64 = 0.5;
+// Transition cost per octave between two voiced frames
+const OCTAVE_COST: f64 = 1.0;
+// Penalty for long lags, keeps subharmonics from winning ties
+const LAG_BIAS: f64 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Candidate {
+    pub frequency: f64,
+    pub cost: f64,
+    pub confidence: f64
+}
+
+impl Candidate {
+    pub fn estimate(&self) -> Estimate {
+        Estimate { frequency: self.frequency, confidence: self.confidence }
+    }
+}
+
+// The deepest minima of the difference function, plus an unvoiced candidate with frequency 0.0.
+// Methods without a difference function contribute their single estimate.
+// Voiced candidates below min_confidence are left out.
+pub fn candidates(data: &[i16], sample_rate: u32, method: Method, count: usize, min_confidence: f64) -> Vec<Candidate> {
+    let mut result = vec![Candidate { frequency: 0.0, cost: UNVOICED_COST, confidence: 0.0 }];
+    let curve = match method.curve(data) {
+        Some(curve) => curve,
+        None => {
+            let estimate = method.detect(data, sample_rate);
+            if (MIN_FREQUENCY..=MAX_FREQUENCY).contains(&estimate.frequency) && estimate.confidence >= min_confidence {
+                result.push(Candidate { frequency: estimate.frequency, cost: 0.0, confidence: estimate.confidence });
+            }
+            return result;
+        }
+    };
+    let min_lag = (sample_rate as f64 / MAX_FREQUENCY).floor() as usize;
+    let max_lag = ((sample_rate as f64 / MIN_FREQUENCY).ceil() as usize).min(curve.len());
+
+    if max_lag <= min_lag {
+        return result;
+    }
+
+    let range = &curve[min_lag..max_lag];
+    let mean = range.iter().sum::<f64>() / range.len() as f64;
+    if mean <= 0.0 {
+        return result;
+    }
+
+    let mut minima: Vec<Candidate> = local_minima(&curve)
+        .into_iter()
+        .filter(|tau| (min_lag..max_lag).contains(tau))
+        .map(|tau| Candidate {
+            frequency: sample_rate as f64 / tau as f64,
+            cost: curve[tau] / mean + LAG_BIAS * tau as f64 / max_lag as f64,
+            confidence: (1.0 - curve[tau] / mean).clamp(0.0, 1.0)
+        })
+        .filter(|c| c.confidence >= min_confidence)
+        .collect();
+    minima.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+    minima.truncate(count);
+
+    result.extend(minima);
+    result
+}
+
+fn transition_cost(from: f64, to: f64) -> f64 {
+    match (from > 0.0, to > 0.0) {
+        (true, true) => OCTAVE_COST * (to / from).log2().abs(),
+        (false, false) => 0.0,
+        _ => VOICING_COST
+    }
+}
+
+// Cheapest path through the candidates of all frames
+pub fn viterbi(frames: &[Vec<Candidate>]) -> Vec<Estimate> {
+    if frames.is_empty() {
+        return Vec::new();
+    }
+
+    let mut costs: Vec<f64> = frames[0].iter().map(|c| c.cost).collect();
+    let mut back: Vec<Vec<usize>> = vec![vec![0; frames[0].len()]];
+
+    for t in 1..frames.len() {
+        let mut next_costs = Vec::with_capacity(frames[t].len());
+        let mut next_back = Vec::with_capacity(frames[t].len());
+        for cand in &frames[t] {
+            let mut best = 0;
+            let mut best_cost = f64::MAX;
+            for (j, prev) in frames[t - 1].iter().enumerate() {
+                let cost = costs[j] + transition_cost(prev.frequency, cand.frequency);
+                if cost < best_cost {
+                    best = j;
+                    best_cost = cost;
+                }
+            }
+            next_costs.push(best_cost + cand.cost);
+            next_back.push(best);
+        }
+        costs = next_costs;
+        back.push(next_back);
+    }
+
+    let mut idx = 0;
+    for (i, cost) in costs.iter().enumerate() {
+        if *cost < costs[idx] {
+            idx = i;
+        }
+    }
+
+    let mut path = vec![Estimate::NONE; frames.len()];
+    for t in (0..frames.len()).rev() {
+        path[t] = frames[t][idx].estimate();
+        idx = back[t][idx];
+    }
+    path
+}
+
+// Median over the voiced frames of an odd sized window, unvoiced frames stay unvoiced
+pub fn median_filter(frequencies: &[f64], width: usize) -> Vec<f64> {
+    let half = width / 2;
+    let mut result = Vec::with_capacity(frequencies.len());
+
+    for (i, f) in frequencies.iter().enumerate() {
+        if *f <= 0.0 {
+            result.push(0.0);
+            continue;
+        }
+        let lo = i.saturating_sub(half);
+        let hi = (i + half + 1).min(frequencies.len());
+        let mut window: Vec<f64> = frequencies[lo..hi].iter().copied().filter(|v| *v > 0.0).collect();
+        window.sort_by(|a, b| a.total_cmp(b));
+        result.push(window[window.len() / 2]);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voiced(frequency: f64, cost: f64) -> Candidate {
+        Candidate { frequency, cost, confidence: 1.0 - cost }
+    }
+
+    #[test]
+    fn viterbi_ignores_a_one_frame_octave_jump() {
+        let unvoiced = Candidate { frequency: 0.0, cost: UNVOICED_COST, confidence: 0.0 };
+        let mut frames = vec![vec![unvoiced, voiced(200.0, 0.1), voiced(400.0, 0.3)]; 5];
+        // The octave above is the locally best candidate in the middle frame only
+        frames[2] = vec![unvoiced, voiced(200.0, 0.2), voiced(400.0, 0.05)];
+
+        let path = viterbi(&frames);
+        assert_eq!(path.len(), 5);
+        for estimate in path {
+            assert_eq!(estimate.frequency, 200.0);
+        }
+    }
+
+    #[test]
+    fn median_removes_an_outlier_and_keeps_the_edges() {
+        let frequencies = [100.0, 100.0, 100.0, 400.0, 100.0, 100.0, 0.0, 100.0];
+        let result = median_filter(&frequencies, 3);
+        assert_eq!(result, vec![100.0, 100.0, 100.0, 100.0, 100.0, 100.0, 0.0, 100.0]);
+    }
+}
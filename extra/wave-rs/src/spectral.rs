@@ -0,0 +1,177 @@
+use std::f64::consts::PI;
+
+use rustfft::{num_complex::Complex, FftPlanner};
+
+use crate::{contour::{MAX_FREQUENCY, MIN_FREQUENCY}, Estimate};
+
+// Number of harmonics multiplied in the harmonic product spectrum
+const HPS_HARMONICS: usize = 5;
+// Zero padding factor of the harmonic product spectrum, for finer bin spacing
+const HPS_PADDING: usize = 4;
+// Magnitudes below this fraction of the peak are raised to it before multiplying
+const HPS_FLOOR: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman
+}
+
+impl Window {
+    pub fn from_name(name: &str) -> Option<Window> {
+        match name {
+            "rect" => Some(Window::Rectangular),
+            "hann" => Some(Window::Hann),
+            "hamming" => Some(Window::Hamming),
+            "blackman" => Some(Window::Blackman),
+            _ => None
+        }
+    }
+
+    pub fn value(&self, n: usize, len: usize) -> f64 {
+        let x = 2.0 * PI * n as f64 / len as f64;
+        match self {
+            Window::Rectangular => 1.0,
+            Window::Hann => 0.5 - 0.5 * f64::cos(x),
+            Window::Hamming => 0.54 - 0.46 * f64::cos(x),
+            Window::Blackman => 0.42 - 0.5 * f64::cos(x) + 0.08 * f64::cos(2.0 * x)
+        }
+    }
+}
+
+// Windowed frame without its DC offset, zero padded to fft_len
+pub fn windowed(data: &[i16], fft_len: usize, window: Window) -> Vec<Complex<f64>> {
+    let mean = data.iter().map(|s| *s as f64).sum::<f64>() / data.len().max(1) as f64;
+    let mut buffer: Vec<Complex<f64>> = vec![Complex::new(0.0, 0.0); fft_len];
+    for (i, s) in data.iter().enumerate().take(fft_len) {
+        buffer[i] = Complex::new((*s as f64 - mean) * window.value(i, data.len()), 0.0);
+    }
+    buffer
+}
+
+// Spectrum of the Hann windowed frame
+pub fn spectrum(data: &[i16], fft_len: usize) -> Vec<Complex<f64>> {
+    let mut buffer = windowed(data, fft_len, Window::Hann);
+    FftPlanner::new().plan_fft_forward(fft_len).process(&mut buffer);
+    buffer
+}
+
+// Offset of the vertex of the parabola through three neighbouring values, in [-0.5, 0.5]
+fn parabolic_offset(left: f64, center: f64, right: f64) -> f64 {
+    let denom = left - 2.0 * center + right;
+    if denom == 0.0 {
+        return 0.0;
+    }
+    (0.5 * (left - right) / denom).clamp(-0.5, 0.5)
+}
+
+fn argmax(vals: &[f64], lo: usize, hi: usize) -> usize {
+    let mut peak = lo;
+    for i in lo..hi {
+        if vals[i] > vals[peak] {
+            peak = i;
+        }
+    }
+    peak
+}
+
+// How far the peak stands out of the searched range, 1.0 when everything else sits at the minimum
+fn prominence(vals: &[f64], peak: usize, lo: usize, hi: usize) -> f64 {
+    let range = &vals[lo..hi];
+    let mean = range.iter().sum::<f64>() / range.len() as f64;
+    let min = range.iter().cloned().fold(f64::MAX, f64::min);
+    if vals[peak] <= min {
+        return 0.0;
+    }
+    ((vals[peak] - mean) / (vals[peak] - min)).clamp(0.0, 1.0)
+}
+
+pub fn cepstrum(data: &[i16]) -> Vec<f64> {
+    let fft_len = data.len().next_power_of_two();
+    let mut buffer: Vec<Complex<f64>> = spectrum(data, fft_len)
+        .iter()
+        .map(|x| Complex::new((x.norm() + 1e-9).ln(), 0.0))
+        .collect();
+    FftPlanner::new().plan_fft_inverse(fft_len).process(&mut buffer);
+    buffer.iter().map(|x| x.re / fft_len as f64).collect()
+}
+
+// Frequency of the strongest cepstral peak in the quefrency range of MIN_FREQUENCY to MAX_FREQUENCY
+pub fn detect_frequency_cepstrum(data: &[i16], sample_rate: u32) -> Estimate {
+    let cep = cepstrum(data);
+    let lo = ((sample_rate as f64 / MAX_FREQUENCY) as usize).max(1);
+    let hi = ((sample_rate as f64 / MIN_FREQUENCY) as usize).min(cep.len() / 2);
+    if hi <= lo + 1 || data.iter().all(|s| *s == 0) {
+        return Estimate::NONE;
+    }
+
+    let peak = argmax(&cep, lo, hi);
+    let quefrency = peak as f64 + parabolic_offset(cep[peak - 1], cep[peak], cep[peak + 1]);
+    Estimate { frequency: sample_rate as f64 / quefrency, confidence: prominence(&cep, peak, lo, hi) }
+}
+
+// Log of the harmonic product spectrum, indexed by bin
+pub fn harmonic_product_spectrum(data: &[i16], fft_len: usize) -> Vec<f64> {
+    let magnitude: Vec<f64> = spectrum(data, fft_len)[..fft_len / 2]
+        .iter()
+        .map(|x| x.norm())
+        .collect();
+    // A missing harmonic costs at most the floor instead of vetoing the fundamental
+    let floor = magnitude.iter().cloned().fold(0.0, f64::max) * HPS_FLOOR + 1e-9;
+    let magnitude: Vec<f64> = magnitude.iter().map(|m| m.max(floor).ln()).collect();
+
+    let len = magnitude.len() / HPS_HARMONICS;
+    let mut hps = vec![0.0; len];
+    for (k, h) in hps.iter_mut().enumerate() {
+        for r in 1..=HPS_HARMONICS {
+            *h += magnitude[k * r];
+        }
+    }
+    hps
+}
+
+pub fn detect_frequency_hps(data: &[i16], sample_rate: u32) -> Estimate {
+    let fft_len = data.len().next_power_of_two() * HPS_PADDING;
+    let hps = harmonic_product_spectrum(data, fft_len);
+    let bin = sample_rate as f64 / fft_len as f64;
+    let lo = ((MIN_FREQUENCY / bin) as usize).max(1);
+    let hi = ((MAX_FREQUENCY / bin) as usize).min(hps.len().saturating_sub(1));
+    if hi <= lo || data.iter().all(|s| *s == 0) {
+        return Estimate::NONE;
+    }
+
+    let peak = argmax(&hps, lo, hi);
+    Estimate {
+        frequency: (peak as f64 + parabolic_offset(hps[peak - 1], hps[peak], hps[peak + 1])) * bin,
+        confidence: prominence(&hps, peak, lo, hi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Eight harmonics falling off as 1 / k
+    fn harmonic_tone(frequency: f64, sample_rate: u32, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|n| {
+                let t = n as f64 / sample_rate as f64;
+                let value: f64 = (1..=8).map(|k| (2.0 * PI * k as f64 * frequency * t).sin() / k as f64).sum();
+                (value * 8000.0).round() as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cepstrum_and_hps_find_the_fundamental() {
+        for frequency in [110.0, 220.0, 330.0] {
+            let data = harmonic_tone(frequency, 16000, 2048);
+            for (name, detect) in [("cepstrum", detect_frequency_cepstrum as fn(&[i16], u32) -> Estimate), ("hps", detect_frequency_hps)] {
+                let estimate = detect(&data, 16000);
+                assert!((estimate.frequency - frequency).abs() < 3.0, "{} found {} Hz for {} Hz", name, estimate.frequency, frequency);
+            }
+        }
+    }
+}
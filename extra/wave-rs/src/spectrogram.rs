@@ -0,0 +1,127 @@
+use std::{fs::File, io::{BufWriter, Write}, process};
+
+use rustfft::FftPlanner;
+
+use crate::{contour::{Contour, ContourOptions}, option_value, read_audio, spectral::{windowed, Window}};
+
+// Dynamic range of the image, quieter bins are black
+const RANGE_DB: f64 = 80.0;
+
+pub struct Spectrogram {
+    pub size: usize,
+    pub hop: usize,
+    pub sample_rate: u32,
+    // Magnitude in dB per frame, size / 2 + 1 bins each
+    pub frames: Vec<Vec<f64>>
+}
+
+impl Spectrogram {
+    pub fn frame_time(&self, frame: usize) -> f64 {
+        (frame * self.hop + self.size / 2) as f64 / self.sample_rate as f64
+    }
+
+    pub fn bin_frequency(&self, bin: usize) -> f64 {
+        bin as f64 * self.sample_rate as f64 / self.size as f64
+    }
+}
+
+pub fn stft(data: &[i16], sample_rate: u32, window: Window, size: usize, hop: usize) -> Spectrogram {
+    let fft = FftPlanner::new().plan_fft_forward(size);
+    let mut frames: Vec<Vec<f64>> = Vec::new();
+
+    let mut start = 0;
+    while start + size <= data.len() {
+        let mut buffer = windowed(&data[start..start + size], size, window);
+        fft.process(&mut buffer);
+        frames.push(buffer[..=size / 2].iter().map(|x| 20.0 * (x.norm() + 1e-9).log10()).collect());
+        start += hop;
+    }
+
+    Spectrogram { size, hop, sample_rate, frames }
+}
+
+fn write_csv(spec: &Spectrogram, contour: Option<&Contour>, max_bin: usize, file_name: &str) {
+    let mut file = BufWriter::new(File::create(file_name).unwrap());
+    for (i, frame) in spec.frames.iter().enumerate() {
+        write!(file, "{:.4}", spec.frame_time(i)).unwrap();
+        if let Some(contour) = contour {
+            write!(file, ",{:.2}", contour.frequency_at(i * spec.hop + spec.size / 2)).unwrap();
+        }
+        for db in &frame[..max_bin] {
+            write!(file, ",{:.2}", db).unwrap();
+        }
+        writeln!(file).unwrap();
+    }
+}
+
+// One column per frame with low frequencies at the bottom, the contour is drawn in red
+fn write_png(spec: &Spectrogram, contour: Option<&Contour>, max_bin: usize, file_name: &str) {
+    let width = spec.frames.len();
+    let height = max_bin;
+    let peak = spec.frames.iter().flatten().cloned().fold(f64::MIN, f64::max);
+    let channels = if contour.is_some() { 3 } else { 1 };
+
+    let mut pixels = vec![0u8; width * height * channels];
+    for (x, frame) in spec.frames.iter().enumerate() {
+        for (bin, db) in frame[..max_bin].iter().enumerate() {
+            let level = (1.0 - (peak - db) / RANGE_DB).clamp(0.0, 1.0);
+            let y = height - 1 - bin;
+            for c in 0..channels {
+                pixels[(y * width + x) * channels + c] = (level * 255.0) as u8;
+            }
+        }
+
+        if let Some(contour) = contour {
+            let frequency = contour.frequency_at(x * spec.hop + spec.size / 2);
+            let bin = (frequency / spec.bin_frequency(1)).round() as usize;
+            if frequency > 0.0 && bin < height {
+                let y = height - 1 - bin;
+                pixels[(y * width + x) * 3..(y * width + x) * 3 + 3].copy_from_slice(&[255, 0, 0]);
+            }
+        }
+    }
+
+    let file = BufWriter::new(File::create(file_name).unwrap());
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(if contour.is_some() { png::ColorType::Rgb } else { png::ColorType::Grayscale });
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().unwrap().write_image_data(&pixels).unwrap();
+}
+
+pub fn run(args: &[String]) {
+    if args.len() < 2 {
+        eprintln!("Usage: wave-rs spectrogram <in.wav> <out.csv|out.png> [--window rect|hann|hamming|blackman] [--size <samples>] [--hop <samples>] [--max-frequency <hz>] [--overlay] [--method <method>] [--smooth <smoothing>]");
+        process::exit(1);
+    }
+
+    let window = Window::from_name(option_value(args, "--window").unwrap_or("hann")).expect("Unknown window");
+    let size: usize = option_value(args, "--size").map(|s| s.parse().expect("Invalid size")).unwrap_or(2048);
+    let hop: usize = option_value(args, "--hop").map(|h| h.parse().expect("Invalid hop")).unwrap_or(size / 4);
+    if size == 0 || hop == 0 {
+        eprintln!("Size and hop must be at least one sample, the default hop is a quarter of the size");
+        process::exit(1);
+    }
+
+    let (sample_rate, samples) = read_audio(&args[0], args);
+    let spec = stft(&samples, sample_rate, window, size, hop);
+    if spec.frames.is_empty() {
+        panic!("File is shorter than one window!");
+    }
+
+    let max_bin = match option_value(args, "--max-frequency") {
+        Some(f) => ((f.parse::<f64>().expect("Invalid frequency") / spec.bin_frequency(1)) as usize + 1).min(size / 2 + 1),
+        None => size / 2 + 1
+    };
+
+    let contour = if args.iter().any(|a| a == "--overlay") {
+        Some(ContourOptions::from_args(args, sample_rate).contour(&samples, sample_rate))
+    } else {
+        None
+    };
+
+    if args[1].ends_with(".png") {
+        write_png(&spec, contour.as_ref(), max_bin, &args[1]);
+    } else {
+        write_csv(&spec, contour.as_ref(), max_bin, &args[1]);
+    }
+}
@@ -0,0 +1,83 @@
+use std::{fs::File, io::{BufReader, ErrorKind, Read, Seek, SeekFrom}};
+
+// Reads the first channel of a 16 bit PCM wav file in blocks instead of loading it whole.
+// Other files have to go through the decoders.
+pub struct WavStream {
+    pub header: wav::Header,
+    reader: BufReader<File>,
+    remaining: u64
+}
+
+impl WavStream {
+    pub fn open(file_name: &str) -> Option<WavStream> {
+        let file = match File::open(file_name) {
+            Ok(file) => file,
+            Err(_) => {
+                panic!("Could not find file!");
+            }
+        };
+        let mut reader = BufReader::new(file);
+
+        let mut riff = [0u8; 12];
+        if reader.read_exact(&mut riff).is_err() || &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+            return None;
+        }
+
+        let mut header: Option<wav::Header> = None;
+        loop {
+            let mut chunk = [0u8; 8];
+            reader.read_exact(&mut chunk).ok()?;
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+
+            match &chunk[0..4] {
+                b"fmt " => {
+                    let mut fmt = vec![0u8; size as usize];
+                    reader.read_exact(&mut fmt).ok()?;
+                    if fmt.len() < 16 {
+                        return None;
+                    }
+                    header = Some(wav::Header {
+                        audio_format: u16::from_le_bytes([fmt[0], fmt[1]]),
+                        channel_count: u16::from_le_bytes([fmt[2], fmt[3]]),
+                        sampling_rate: u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]),
+                        bytes_per_second: u32::from_le_bytes([fmt[8], fmt[9], fmt[10], fmt[11]]),
+                        bytes_per_sample: u16::from_le_bytes([fmt[12], fmt[13]]),
+                        bits_per_sample: u16::from_le_bytes([fmt[14], fmt[15]])
+                    });
+                    if size % 2 == 1 {
+                        reader.seek(SeekFrom::Current(1)).unwrap();
+                    }
+                }
+                b"data" => {
+                    let header = header?;
+                    if header.audio_format != wav::header::WAV_FORMAT_PCM || header.bits_per_sample != 16 {
+                        return None;
+                    }
+                    return Some(WavStream { header, reader, remaining: size });
+                }
+                _ => {
+                    reader.seek(SeekFrom::Current((size + size % 2) as i64)).unwrap();
+                }
+            }
+        }
+    }
+
+    // Appends up to count samples to the buffer, returns how many were read
+    pub fn read(&mut self, buffer: &mut Vec<i16>, count: usize) -> usize {
+        let frame_bytes = self.header.bytes_per_sample as usize;
+        let mut frame = vec![0u8; frame_bytes];
+        let mut read = 0;
+
+        while read < count && self.remaining >= frame_bytes as u64 {
+            match self.reader.read_exact(&mut frame) {
+                Ok(()) => {}
+                Err(error) if error.kind() == ErrorKind::UnexpectedEof => break,
+                Err(error) => panic!("{}", error)
+            }
+            buffer.push(i16::from_le_bytes([frame[0], frame[1]]));
+            self.remaining -= frame_bytes as u64;
+            read += 1;
+        }
+        read
+    }
+}
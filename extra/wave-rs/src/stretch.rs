@@ -0,0 +1,113 @@
+use std::{f64::consts::PI, process};
+
+use crate::{option_value, read_audio, write_wav, AMDF, ASDF};
+
+// Length of the overlapping grains, long enough to hold a few periods of low voices
+const FRAME_MS: usize = 40;
+// How far a grain may move from its nominal position to line up with the previous one
+const SEARCH_MS: usize = 5;
+
+// Difference between the samples at a and at b over len samples, using one of the lag difference functions
+fn difference(data: &[i16], a: usize, b: usize, len: usize, similarity: fn(&[i16], usize) -> f64) -> f64 {
+    let lo = a.min(b);
+    let tau = a.max(b) - lo;
+    similarity(&data[lo..lo + tau + len + 1], tau)
+}
+
+// Waveform similarity overlap-add, factor is the ratio of output to input duration
+pub fn wsola(data: &[i16], sample_rate: u32, factor: f64, similarity: fn(&[i16], usize) -> f64) -> Vec<f64> {
+    let frame = ((sample_rate as usize) / 1000 * FRAME_MS).max(2);
+    let hop = frame / 2;
+    let tolerance = (sample_rate as usize) / 1000 * SEARCH_MS;
+    let compare = frame / 2;
+
+    let len = (data.len() as f64 * factor) as usize;
+    let mut out = vec![0.0; len];
+    let mut weights = vec![0.0; len];
+    if data.len() < frame + hop + compare + 1 {
+        return out;
+    }
+    let window: Vec<f64> = (0..frame).map(|i| 0.5 - 0.5 * (2.0 * PI * i as f64 / frame as f64).cos()).collect();
+
+    let last = data.len() - frame - 1;
+    let mut start = 0;
+    let mut k = 0;
+    while k * hop + frame <= len {
+        for i in 0..frame {
+            out[k * hop + i] += data[start + i] as f64 * window[i];
+            weights[k * hop + i] += window[i];
+        }
+        k += 1;
+
+        // The grain that continues the one just placed, the next grain should look like it
+        let natural = start + hop;
+        if natural + compare + tolerance >= data.len() {
+            break;
+        }
+        let nominal = ((k * hop) as f64 / factor).round() as usize;
+        let lo = nominal.saturating_sub(tolerance);
+        let hi = (nominal + tolerance).min(last).min(data.len() - compare - 1);
+        if lo > hi {
+            break;
+        }
+
+        start = (lo..=hi)
+            .min_by(|a, b| {
+                difference(data, natural, *a, compare, similarity)
+                    .total_cmp(&difference(data, natural, *b, compare, similarity))
+            })
+            .unwrap();
+    }
+
+    for (o, w) in out.iter_mut().zip(weights.iter()) {
+        if *w > 1e-3 {
+            *o /= w;
+        }
+    }
+    out
+}
+
+pub fn run(args: &[String]) {
+    if args.len() < 2 {
+        eprintln!("Usage: wave-rs stretch <in.wav> <out.wav> [--factor <ratio>] [--method asdf|amdf]");
+        process::exit(1);
+    }
+
+    let factor: f64 = option_value(args, "--factor").map(|f| f.parse().expect("Invalid factor")).unwrap_or(2.0);
+    if factor <= 0.0 {
+        panic!("Factor must be positive!");
+    }
+    let similarity: fn(&[i16], usize) -> f64 = match option_value(args, "--method").unwrap_or("asdf") {
+        "asdf" => ASDF,
+        "amdf" => AMDF,
+        _ => panic!("Unknown method")
+    };
+
+    let (sample_rate, samples) = read_audio(&args[0], args);
+    let out = wsola(&samples, sample_rate, factor, similarity);
+
+    let out: Vec<i16> = out.iter()
+        .map(|s| s.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16)
+        .collect();
+    println!("Stretched {} samples to {}", samples.len(), out.len());
+    write_wav(&args[1], sample_rate, out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detect_frequency_amdf;
+
+    #[test]
+    fn stretch_keeps_pitch() {
+        let sample_rate = 44100;
+        let data: Vec<i16> = (0..sample_rate)
+            .map(|i| (10000.0 * (2.0 * PI * 441.0 * i as f64 / sample_rate as f64).sin()) as i16)
+            .collect();
+        let out = wsola(&data, sample_rate as u32, 1.5, ASDF);
+        assert_eq!(out.len(), data.len() * 3 / 2);
+
+        let middle: Vec<i16> = out[20000..22200].iter().map(|s| s.round() as i16).collect();
+        assert_eq!(detect_frequency_amdf(&middle, sample_rate as u32).frequency, 441.0);
+    }
+}
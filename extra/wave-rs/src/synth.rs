@@ -0,0 +1,187 @@
+use std::{f64::consts::PI, process};
+
+use crate::{option_value, write_wav_f64};
+
+// Loop gain of the plucked string
+pub const PLUCK_FEEDBACK: f64 = 0.996;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    Noise,
+    Pluck
+}
+
+impl Waveform {
+    pub fn from_name(name: &str) -> Option<Waveform> {
+        match name {
+            "sine" => Some(Waveform::Sine),
+            "saw" => Some(Waveform::Saw),
+            "square" => Some(Waveform::Square),
+            "triangle" => Some(Waveform::Triangle),
+            "noise" => Some(Waveform::Noise),
+            "pluck" => Some(Waveform::Pluck),
+            _ => None
+        }
+    }
+}
+
+// Xorshift generator so that noise is the same for the same seed on every machine
+pub struct Noise {
+    state: u32
+}
+
+impl Noise {
+    pub fn new(seed: u32) -> Noise {
+        Noise { state: seed.max(1) }
+    }
+
+    // Uniform in [-1, 1)
+    pub fn next(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f64 / 2147483648.0 - 1.0
+    }
+}
+
+pub struct SynthParams {
+    pub waveform: Waveform,
+    pub frequency: f64,
+    pub duration: f64,
+    pub sample_rate: u32,
+    pub amplitude: f64,
+    pub noise: f64,
+    pub vibrato_rate: f64,
+    // Vibrato depth in cents
+    pub vibrato_depth: f64,
+    pub seed: u32
+}
+
+pub fn synthesize(params: &SynthParams) -> Vec<f64> {
+    let sr = params.sample_rate as f64;
+    let len = (params.duration * sr) as usize;
+    let mut noise = Noise::new(params.seed);
+    let mut out = Vec::with_capacity(len);
+
+    // Delay line of the plucked string, long enough for the lowest vibrato frequency
+    let lowest = params.frequency * 2f64.powf(-params.vibrato_depth.abs() / 1200.0);
+    let mut string = vec![0.0; (sr / lowest) as usize + 3];
+    if params.waveform == Waveform::Pluck {
+        let period = ((sr / params.frequency) as usize).min(string.len());
+        for s in string.iter_mut().take(period) {
+            *s = noise.next();
+        }
+    }
+    let mut write = 0;
+
+    let mut phase: f64 = 0.0;
+    for n in 0..len {
+        let t = n as f64 / sr;
+        let cents = params.vibrato_depth * f64::sin(2.0 * PI * params.vibrato_rate * t);
+        let frequency = params.frequency * 2f64.powf(cents / 1200.0);
+
+        let value = match params.waveform {
+            Waveform::Sine => f64::sin(2.0 * PI * phase),
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Noise => noise.next(),
+            Waveform::Pluck => {
+                // The two tap average delays by half a sample, the taps sit half a sample either side of the period
+                let len = string.len();
+                let pos = (write + len) as f64 - (sr / frequency - 0.5);
+                let i = pos.floor() as usize;
+                let fr = pos.fract();
+                let x0 = string[(i + len - 1) % len];
+                let x1 = string[i % len];
+                let x2 = string[(i + 1) % len];
+                let delayed = (1.0 - fr) * x1 + fr * x2;
+                let older = (1.0 - fr) * x0 + fr * x1;
+                let value = PLUCK_FEEDBACK * 0.5 * (delayed + older);
+                string[write] = value;
+                write = (write + 1) % len;
+                value
+            }
+        };
+
+        phase += frequency / sr;
+        phase -= phase.floor();
+
+        out.push(params.amplitude * value + params.noise * noise.next());
+    }
+    out
+}
+
+pub fn run(args: &[String]) {
+    if args.is_empty() {
+        eprintln!("Usage: wave-rs synth <out.wav> [--wave sine|saw|square|triangle|noise|pluck] [--frequency <hz>] [--duration <s>] [--rate <hz>] [--bits 8|16|24|32] [--amplitude <0-1>] [--noise <0-1>] [--vibrato-rate <hz>] [--vibrato-depth <cents>] [--seed <n>]");
+        process::exit(1);
+    }
+
+    let number = |name: &str, default: f64| -> f64 {
+        option_value(args, name).map(|v| v.parse().expect("Invalid number")).unwrap_or(default)
+    };
+    let params = SynthParams {
+        waveform: Waveform::from_name(option_value(args, "--wave").unwrap_or("sine")).expect("Unknown waveform"),
+        frequency: number("--frequency", 440.0),
+        duration: number("--duration", 1.0),
+        sample_rate: number("--rate", 44100.0) as u32,
+        amplitude: number("--amplitude", 0.5),
+        noise: number("--noise", 0.0),
+        vibrato_rate: number("--vibrato-rate", 5.0),
+        vibrato_depth: number("--vibrato-depth", 0.0),
+        seed: number("--seed", 1.0) as u32
+    };
+    let bits = number("--bits", 16.0) as u16;
+
+    let data = synthesize(&params);
+    write_wav_f64(&args[0], params.sample_rate, bits, &data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Strongest frequency within 50 cents of the expected one, in steps of 0.5 cents
+    fn measured_frequency(signal: &[f64], sr: f64, expected: f64) -> f64 {
+        let magnitude = |f: f64| {
+            let w = 2.0 * PI * f / sr;
+            let (mut re, mut im) = (0.0, 0.0);
+            for (n, x) in signal.iter().enumerate() {
+                let window = 0.5 - 0.5 * (2.0 * PI * n as f64 / signal.len() as f64).cos();
+                re += x * window * (w * n as f64).cos();
+                im -= x * window * (w * n as f64).sin();
+            }
+            re * re + im * im
+        };
+        (-100..=100)
+            .map(|k| expected * 2f64.powf(k as f64 * 0.5 / 1200.0))
+            .max_by(|a, b| magnitude(*a).total_cmp(&magnitude(*b)))
+            .unwrap()
+    }
+
+    #[test]
+    fn pluck_plays_at_its_frequency() {
+        for frequency in [220.0, 1000.0] {
+            let params = SynthParams {
+                waveform: Waveform::Pluck,
+                frequency,
+                duration: 0.5,
+                sample_rate: 44100,
+                amplitude: 0.5,
+                noise: 0.0,
+                vibrato_rate: 5.0,
+                vibrato_depth: 0.0,
+                seed: 1
+            };
+            let data = synthesize(&params);
+            let measured = measured_frequency(&data[4410..22050], 44100.0, frequency);
+            let cents = 1200.0 * (measured / frequency).log2();
+            assert!(cents.abs() < 1.0, "{} Hz pluck is {} cents off", frequency, cents);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::{fs::File, io::Write, process};
+
+use crate::{contour::{Contour, ContourOptions}, option_value, psola::pitch_marks, read_audio};
+
+// Table length of the WaveTable oscillator in osc-rs
+const TABLE_LEN: usize = 512;
+
+// Linear interpolation between the neighbouring samples
+fn sample_at(data: &[f64], pos: f64) -> f64 {
+    let i = pos.floor() as usize;
+    let fract = pos - i as f64;
+    if i + 1 >= data.len() {
+        return data[data.len() - 1];
+    }
+    (1.0 - fract) * data[i] + fract * data[i + 1]
+}
+
+// One period starting at the mark, resampled to len samples without DC and normalized to a peak of 1.0
+pub fn extract_cycle(data: &[f64], start: usize, period: f64, len: usize) -> Vec<f64> {
+    // Neighbouring periods never match exactly, the difference is spread over the cycle so the table wraps without a step
+    let drift = sample_at(data, start as f64 + period) - data[start];
+    let mut cycle: Vec<f64> = (0..len)
+        .map(|k| sample_at(data, start as f64 + k as f64 * period / len as f64) - drift * k as f64 / len as f64)
+        .collect();
+
+    let mean = cycle.iter().sum::<f64>() / len as f64;
+    let peak = cycle.iter().map(|s| (s - mean).abs()).fold(0.0, f64::max);
+    for s in cycle.iter_mut() {
+        *s = if peak > 0.0 { (*s - mean) / peak } else { 0.0 };
+    }
+    cycle
+}
+
+// Cycles spread evenly over the voiced part of the recording, each from one pitch mark to the next
+pub fn extract_cycles(data: &[f64], contour: &Contour, sample_rate: u32, count: usize, len: usize) -> Vec<Vec<f64>> {
+    let marks = pitch_marks(data, contour, sample_rate);
+    let voiced: Vec<(usize, f64)> = marks
+        .windows(2)
+        .filter_map(|pair| {
+            let frequency = contour.frequency_at(pair[0]);
+            if frequency <= 0.0 {
+                return None;
+            }
+            // Mark to mark keeps both ends of the cycle on a peak, the contour only decides which pairs are periods
+            let period = (pair[1] - pair[0]) as f64;
+            let expected = sample_rate as f64 / frequency;
+            if (period - expected).abs() > 0.25 * expected || pair[1] + 1 >= data.len() {
+                return None;
+            }
+            Some((pair[0], period))
+        })
+        .collect();
+
+    let count = count.min(voiced.len());
+    (0..count)
+        .map(|i| {
+            let (mark, period) = voiced[(2 * i + 1) * voiced.len() / (2 * count)];
+            extract_cycle(data, mark, period, len)
+        })
+        .collect()
+}
+
+pub fn run(args: &[String]) {
+    if args.len() < 2 {
+        eprintln!("Usage: wave-rs wavetable <in.wav> <out.wt> [--cycles <count>] [--length <samples>] [--method <method>] [--smooth <smoothing>] [--min-confidence <0-1>]");
+        process::exit(1);
+    }
+
+    let count: usize = option_value(args, "--cycles").map(|c| c.parse().expect("Invalid cycle count")).unwrap_or(1);
+    let len: usize = option_value(args, "--length").map(|l| l.parse().expect("Invalid table length")).unwrap_or(TABLE_LEN);
+
+    let (sample_rate, samples) = read_audio(&args[0], args);
+    let contour = ContourOptions::from_args(args, sample_rate).contour(&samples, sample_rate);
+    let data: Vec<f64> = samples.iter().map(|s| *s as f64).collect();
+
+    let cycles = extract_cycles(&data, &contour, sample_rate, count, len);
+    if cycles.is_empty() {
+        panic!("Could not find a voiced period!");
+    }
+
+    // One table per line, the format read by the terrain oscillator
+    let mut file = File::create(&args[1]).unwrap();
+    writeln!(file, "# {} cycles of {} samples from {}", cycles.len(), len, args[0]).unwrap();
+    for cycle in &cycles {
+        let values: Vec<String> = cycle.iter().map(|s| format!("{:.6}", s)).collect();
+        writeln!(file, "{}", values.join(",")).unwrap();
+    }
+
+    println!("Extracted {} cycles", cycles.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycle_of_a_sine_is_a_sine() {
+        let period = 100.5;
+        let data: Vec<f64> = (0..1000).map(|i| 3.0 + 2.0 * (std::f64::consts::TAU * i as f64 / period).sin()).collect();
+        let cycle = extract_cycle(&data, 0, period, 64);
+        for (k, s) in cycle.iter().enumerate() {
+            let expected = (std::f64::consts::TAU * k as f64 / 64.0).sin();
+            assert!((s - expected).abs() < 0.01, "{} != {}", s, expected);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+// Runs the wave-rs binary on the bundled samples and compares against the stored outputs in tests/golden
+
+use std::{fs, path::{Path, PathBuf}, process::Command};
+
+const TOLERANCE: f64 = 0.011;
+
+fn manifest_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+// Fresh working directory, the binary writes its csv files into the current directory
+fn work_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wave-rs-golden-{}-{}", name, std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn wave_rs(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_wave-rs"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "wave-rs {:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn frequency(stdout: &str, label: &str) -> f64 {
+    let line = stdout.lines()
+        .find(|l| l.starts_with(label))
+        .unwrap_or_else(|| panic!("missing {} in {:?}", label, stdout));
+    line[label.len()..].trim().parse().unwrap()
+}
+
+fn read_csv(path: &Path) -> Vec<Vec<f64>> {
+    fs::read_to_string(path)
+        .unwrap_or_else(|_| panic!("could not read {:?}", path))
+        .lines()
+        .map(|l| l.split(',').map(|v| v.parse().unwrap()).collect())
+        .collect()
+}
+
+fn assert_csv_matches(actual: &Path, golden: &Path) {
+    let actual_rows = read_csv(actual);
+    let golden_rows = read_csv(golden);
+    assert_eq!(actual_rows.len(), golden_rows.len(), "row count of {:?}", golden);
+
+    for (i, (a, g)) in actual_rows.iter().zip(golden_rows.iter()).enumerate() {
+        assert_eq!(a.len(), g.len(), "column count of {:?} line {}", golden, i + 1);
+        for (x, y) in a.iter().zip(g.iter()) {
+            assert!((x - y).abs() <= TOLERANCE, "{:?} line {}: {} != {}", golden, i + 1, x, y);
+        }
+    }
+}
+
+fn check_analysis(name: &str, amdf: f64, asdf: f64) {
+    let dir = work_dir(name);
+    let input = manifest_dir().join(format!("{}.wav", name));
+    let stdout = wave_rs(&dir, &[input.to_str().unwrap()]);
+
+    assert_eq!(frequency(&stdout, "AMDF Frequency:"), amdf);
+    assert_eq!(frequency(&stdout, "ASDF Frequency:"), asdf);
+
+    let golden = manifest_dir().join("tests/golden").join(name);
+    for csv in ["samples.csv", "amdf.csv", "asdf.csv"] {
+        assert_csv_matches(&dir.join(csv), &golden.join(csv));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn note_analysis_matches_golden() {
+    // The ASDF estimate of note.wav is wrong, 5.9 times the 147 Hz fundamental and not one of its harmonics.
+    // It is kept here so that changes to it are noticed
+    check_analysis("note", 147.0, 864.7058823529412);
+}
+
+#[test]
+fn saw_analysis_matches_golden() {
+    check_analysis("saw", 441.0, 441.0);
+}
+
+#[test]
+fn saw_contour_matches_golden() {
+    let dir = work_dir("saw-contour");
+    let input = manifest_dir().join("saw.wav");
+    let output = dir.join("contour.csv");
+    wave_rs(&dir, &["contour", input.to_str().unwrap(), output.to_str().unwrap()]);
+
+    assert_csv_matches(&output, &manifest_dir().join("tests/golden/saw/contour.csv"));
+    fs::remove_dir_all(&dir).unwrap();
+}